@@ -0,0 +1,138 @@
+//! Transcripts of messages that are about to be purged, kept on disk as a
+//! record for the staff. Each one is written twice, as JSON for tools and as
+//! Markdown for people.
+
+use chrono::{DateTime, Utc};
+use json;
+use serenity::model::id::ChannelId;
+
+use std::env;
+use std::fmt::Write as FmtWrite;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use discord::ChannelMessage;
+use state::storage::invalid_data;
+
+/// Where a transcript ended up
+pub struct Transcript {
+    pub json: PathBuf,
+    pub markdown: PathBuf,
+}
+
+#[derive(Serialize)]
+struct JsonTranscript<'a> {
+    channel: ChannelId,
+    written: String,
+    messages: Vec<JsonMessage<'a>>,
+}
+
+#[derive(Serialize)]
+struct JsonMessage<'a> {
+    id: u64,
+    author: u64,
+    author_tag: &'a str,
+    timestamp: String,
+    edited: Option<String>,
+    content: &'a str,
+    attachments: &'a [String],
+    pinned: bool,
+}
+
+/// The directory from `ARCHIVE_DIR`, or `archives` if that isn't set
+#[cfg(not(test))]
+pub fn dir() -> PathBuf {
+    env::var("ARCHIVE_DIR")
+        .unwrap_or_else(|_| "archives".into())
+        .into()
+}
+
+/// Tests keep their transcripts out of the way, and out of each other's
+#[cfg(test)]
+pub fn dir() -> PathBuf {
+    let test = ::std::thread::current()
+        .name()
+        .unwrap_or("test")
+        .replace(':', "_");
+    env::temp_dir()
+        .join("transcrossroadsbot-archives")
+        .join(test)
+}
+
+/// Writes out `messages` from `channel`, oldest first
+pub fn write(
+    channel: ChannelId,
+    messages: &[ChannelMessage],
+    now: DateTime<Utc>,
+) -> io::Result<Transcript> {
+    let mut messages = messages.iter().collect::<Vec<_>>();
+    messages.sort_by_key(|msg| (msg.timestamp, msg.id));
+
+    let dir = dir();
+    fs::create_dir_all(&dir)?;
+    let name = format!("purge-{}-{}", channel.0, now.format("%Y%m%d-%H%M%S"));
+    let transcript = Transcript {
+        json: dir.join(format!("{}.json", name)),
+        markdown: dir.join(format!("{}.md", name)),
+    };
+
+    let data = JsonTranscript {
+        channel,
+        written: now.to_rfc3339(),
+        messages: messages
+            .iter()
+            .map(|msg| JsonMessage {
+                id: msg.id.0,
+                author: msg.author.0,
+                author_tag: &msg.author_tag,
+                timestamp: msg.timestamp.to_rfc3339(),
+                edited: msg.edited.map(|edited| edited.to_rfc3339()),
+                content: &msg.content,
+                attachments: &msg.attachments,
+                pinned: msg.pinned,
+            })
+            .collect(),
+    };
+    let data = json::to_vec_pretty(&data).map_err(invalid_data)?;
+    File::create(&transcript.json)?.write_all(&data)?;
+
+    File::create(&transcript.markdown)?.write_all(markdown(channel, &messages, now).as_bytes())?;
+
+    Ok(transcript)
+}
+
+fn markdown(channel: ChannelId, messages: &[&ChannelMessage], now: DateTime<Utc>) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "# Transcript of channel {}", channel.0);
+    let _ = writeln!(
+        out,
+        "Written {}, {} messages",
+        now.to_rfc2822(),
+        messages.len()
+    );
+
+    for msg in messages {
+        let _ = write!(
+            out,
+            "\n**{}** ({}) - {}",
+            msg.author_tag,
+            msg.author.0,
+            msg.timestamp.to_rfc2822()
+        );
+        if let Some(edited) = msg.edited {
+            let _ = write!(out, " (edited {})", edited.to_rfc2822());
+        }
+        if msg.pinned {
+            out.push_str(" (pinned)");
+        }
+        out.push('\n');
+        for line in msg.content.lines() {
+            let _ = writeln!(out, "> {}", line);
+        }
+        for url in &msg.attachments {
+            let _ = writeln!(out, "Attachment: {}", url);
+        }
+    }
+    out
+}
@@ -1,7 +1,33 @@
 use rand::Rng;
+use serenity::model::prelude::*;
+use serenity::prelude::*;
 
 use std::collections::{BTreeMap, BTreeSet};
-use std::fmt::Debug;
+use std::fmt::{self, Debug};
+
+use grammar;
+use grammar::ast::Command;
+use rng;
+use state::rolls;
+
+const MAX_DICE: u32 = 100;
+const MAX_SIDES: u32 = 1000;
+const MAX_ITERATIONS: u32 = 10;
+const MAX_EXTRA_DICE: u32 = 100;
+const MAX_TERMS: usize = 20;
+const MAX_CONSTANT: i32 = 1_000_000;
+
+#[derive(Debug)]
+pub struct DiceExpression {
+    /// Each term is paired with its sign, either 1 or -1
+    pub terms: Vec<(i32, DiceTerm)>,
+}
+
+#[derive(Debug)]
+pub enum DiceTerm {
+    Roll(DiceRoll),
+    Constant(i32),
+}
 
 #[derive(Debug)]
 pub struct DiceRoll {
@@ -9,32 +35,308 @@ pub struct DiceRoll {
     pub modifiers: Vec<Box<DiceModifier>>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
 pub struct DiceSpecifier {
     pub num: u32,
     pub sides: u32,
 }
 
-pub struct RollRequest {
-    pub dice: Box<Dice>,
+pub struct RollRequest<'a> {
+    pub dice: &'a mut Dice,
     pub roll_values: BTreeMap<(u32, u32), i32>,
     pub spec: DiceSpecifier,
     pub iterations: u32,
     pub cancellations: BTreeSet<(u32, u32)>,
     pub total_modifiers: BTreeMap<u32, i32>,
+    pub successes: Option<BTreeMap<u32, i32>>,
 }
 
 pub struct Roll {
+    pub spec: DiceSpecifier,
     pub results: Vec<Vec<(i32, bool)>>,
+    pub totals: Vec<i32>,
+    pub counts_successes: bool,
+}
+
+pub struct ExpressionRoll {
+    pub terms: Vec<(i32, TermResult)>,
+    pub total: i32,
+}
+
+pub enum TermResult {
+    Roll(Roll),
+    Constant(i32),
+}
+
+impl DiceExpression {
+    pub fn new(first: DiceTerm, rest: Vec<(i32, DiceTerm)>) -> DiceExpression {
+        let mut terms = Vec::with_capacity(rest.len() + 1);
+        terms.push((1, first));
+        terms.extend(rest);
+        DiceExpression { terms }
+    }
+
+    pub fn roll(&self, dice: &mut Dice) -> Result<ExpressionRoll, String> {
+        self.check_limits()?;
+
+        let mut terms = Vec::with_capacity(self.terms.len());
+        let mut total = 0i32;
+        for &(sign, ref term) in &self.terms {
+            let result = match term {
+                DiceTerm::Roll(roll) => TermResult::Roll(roll.roll(dice)?),
+                DiceTerm::Constant(value) => TermResult::Constant(*value),
+            };
+            total = total.saturating_add(sign.saturating_mul(result.total()));
+            terms.push((sign, result));
+        }
+
+        Ok(ExpressionRoll { terms, total })
+    }
+
+    pub fn check_limits(&self) -> Result<(), String> {
+        if self.terms.len() > MAX_TERMS {
+            return Err(format!(
+                "That's too much math for me, keep it to {} terms please~",
+                MAX_TERMS
+            ));
+        }
+
+        let num_dice: u64 = self
+            .terms
+            .iter()
+            .map(|term| match term {
+                (_, DiceTerm::Roll(roll)) => roll.roll_spec.num as u64,
+                (_, DiceTerm::Constant(_)) => 0,
+            })
+            .sum();
+        if num_dice > MAX_DICE as u64 {
+            return Err(format!("I only have {} dice, sorry!", MAX_DICE));
+        }
+
+        for term in &self.terms {
+            if let (_, DiceTerm::Constant(value)) = term {
+                check_number(*value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Numbers in an expression, or that one gets compared to, have to stay
+/// small enough to add up without overflowing
+pub fn check_number(value: i32) -> Result<(), String> {
+    if value > MAX_CONSTANT {
+        return Err(format!(
+            "{} is too big for me, keep numbers to a million or less please~",
+            value
+        ));
+    }
+    Ok(())
+}
+
+impl fmt::Display for DiceExpression {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        for (i, &(sign, ref term)) in self.terms.iter().enumerate() {
+            match (i, sign < 0) {
+                (0, false) => (),
+                (0, true) => write!(fmt, "-")?,
+                (_, false) => write!(fmt, " + ")?,
+                (_, true) => write!(fmt, " - ")?,
+            }
+            match term {
+                DiceTerm::Roll(roll) => write!(fmt, "{}", roll)?,
+                DiceTerm::Constant(value) => write!(fmt, "{}", value)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl TermResult {
+    pub fn total(&self) -> i32 {
+        match self {
+            TermResult::Roll(roll) => roll.totals.iter().sum(),
+            TermResult::Constant(value) => *value,
+        }
+    }
 }
 
 impl DiceRoll {
-    pub fn roll() -> Result<Roll, String> {
-        unimplemented!()
+    pub fn new(roll_spec: DiceSpecifier) -> DiceRoll {
+        DiceRoll::with_modifiers(roll_spec, Vec::new())
+    }
+
+    pub fn with_modifiers(
+        roll_spec: DiceSpecifier,
+        mut modifiers: Vec<Box<DiceModifier>>,
+    ) -> DiceRoll {
+        // Rerolls have to happen before anything looks at the values, and
+        // keep/drop has to see the exploded dice, so don't trust the order
+        // they were typed in.
+        modifiers.sort_by_key(|m| m.stage());
+        DiceRoll {
+            roll_spec,
+            modifiers,
+        }
+    }
+
+    pub fn roll(&self, dice: &mut Dice) -> Result<Roll, String> {
+        self.roll_as(self.roll_spec, dice)
+    }
+
+    /// Runs this roll's modifiers over a different set of dice. The odds
+    /// calculator uses this to work things out one die at a time.
+    pub fn roll_as(&self, spec: DiceSpecifier, dice: &mut Dice) -> Result<Roll, String> {
+        let mut request = RollRequest::new(dice, spec);
+
+        for modifier in &self.modifiers {
+            modifier.pre_roll(&mut request)?;
+        }
+
+        request.check_limits()?;
+        request.roll_all();
+
+        for modifier in &self.modifiers {
+            modifier.post_roll(&mut request)?;
+        }
+
+        Ok(request.finish())
+    }
+}
+
+impl<'a> RollRequest<'a> {
+    pub fn new(dice: &'a mut Dice, spec: DiceSpecifier) -> RollRequest<'a> {
+        RollRequest {
+            dice,
+            roll_values: BTreeMap::new(),
+            spec,
+            iterations: 1,
+            cancellations: BTreeSet::new(),
+            total_modifiers: BTreeMap::new(),
+            successes: None,
+        }
+    }
+
+    pub fn roll_one(&mut self) -> i32 {
+        self.spec.roll_one(self.dice)
+    }
+
+    /// Rolls one more die for the given iteration and adds it to the end
+    pub fn add_die(&mut self, iteration: u32) -> Result<(u32, u32), String> {
+        let next = self
+            .roll_values
+            .range((iteration, 0)..(iteration + 1, 0))
+            .next_back()
+            .map(|(&(_, j), _)| j + 1)
+            .unwrap_or(0);
+
+        if next >= self.spec.num + MAX_EXTRA_DICE {
+            return Err("That's way too many dice, I'm giving up!".into());
+        }
+
+        let value = self.roll_one();
+        self.roll_values.insert((iteration, next), value);
+        Ok((iteration, next))
+    }
+
+    /// All of the dice in an iteration that haven't been cancelled
+    pub fn live_dice(&self, iteration: u32) -> Vec<((u32, u32), i32)> {
+        self.roll_values
+            .range((iteration, 0)..(iteration + 1, 0))
+            .filter(|&(key, _)| !self.cancellations.contains(key))
+            .map(|(&key, &value)| (key, value))
+            .collect()
+    }
+
+    fn check_limits(&self) -> Result<(), String> {
+        self.spec.check_limits()?;
+        if self.iterations > MAX_ITERATIONS {
+            return Err(format!(
+                "I can only roll that up to {} times at once",
+                MAX_ITERATIONS
+            ));
+        }
+        Ok(())
+    }
+
+    fn roll_all(&mut self) {
+        for i in 0..self.iterations {
+            for j in 0..self.spec.num {
+                if !self.roll_values.contains_key(&(i, j)) {
+                    let value = self.roll_one();
+                    self.roll_values.insert((i, j), value);
+                }
+            }
+        }
+    }
+
+    fn finish(self) -> Roll {
+        let mut results = vec![Vec::new(); self.iterations as usize];
+        for (&(i, j), &value) in &self.roll_values {
+            let cancelled = self.cancellations.contains(&(i, j));
+            results[i as usize].push((value, cancelled));
+        }
+
+        let totals = results
+            .iter()
+            .enumerate()
+            .map(|(i, dice)| {
+                let i = i as u32;
+                let base = match self.successes {
+                    Some(ref successes) => successes.get(&i).cloned().unwrap_or(0),
+                    None => dice
+                        .iter()
+                        .filter(|&&(_, cancelled)| !cancelled)
+                        .map(|&(value, _)| value)
+                        .sum(),
+                };
+                base + self.total_modifiers.get(&i).cloned().unwrap_or(0)
+            })
+            .collect();
+
+        Roll {
+            spec: self.spec,
+            results,
+            totals,
+            counts_successes: self.successes.is_some(),
+        }
     }
 }
 
 impl DiceSpecifier {
+    /// Parses `NdS`, where a missing N means one die. Numbers too big to
+    /// parse are saturated so that the limit checks reject them later.
+    pub fn parse(s: &str) -> DiceSpecifier {
+        let mut parts = s.split("d");
+        let num = match parts.next().unwrap() {
+            "" => 1,
+            num => num.parse().unwrap_or(u32::max_value()),
+        };
+        let sides = parts.next().unwrap().parse().unwrap_or(u32::max_value());
+        assert!(parts.next().is_none());
+
+        DiceSpecifier { num, sides }
+    }
+
+    pub fn check_limits(&self) -> Result<(), String> {
+        if self.num == 0 {
+            return Err("I can't roll zero dice, silly~".into());
+        }
+        if self.num > MAX_DICE {
+            return Err(format!("I only have {} dice, sorry!", MAX_DICE));
+        }
+        if self.sides == 0 {
+            return Err("A die needs at least one side!".into());
+        }
+        if self.sides > MAX_SIDES {
+            return Err(format!(
+                "My biggest die only has {} sides, sorry!",
+                MAX_SIDES
+            ));
+        }
+        Ok(())
+    }
+
     pub fn roll_one(&self, dice: &mut Dice) -> i32 {
         dice.roll(self.sides)
     }
@@ -44,7 +346,43 @@ impl DiceSpecifier {
     }
 }
 
-pub trait DiceModifier: Debug {
+impl fmt::Display for DiceSpecifier {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}d{}", self.num, self.sides)
+    }
+}
+
+impl fmt::Display for DiceRoll {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}", self.roll_spec)?;
+        for modifier in self.modifiers.iter().filter(|m| m.attached()) {
+            write!(fmt, "{}", modifier)?;
+        }
+        for modifier in self.modifiers.iter().filter(|m| !m.attached()) {
+            write!(fmt, "{}", modifier)?;
+        }
+        Ok(())
+    }
+}
+
+pub trait DiceModifier: Debug + fmt::Display {
+    /// Modifiers are applied in ascending order of stage
+    fn stage(&self) -> u32 {
+        0
+    }
+
+    /// Whether the modifier only ever looks at one die at a time, which
+    /// lets the odds calculator work out a single die and add it up
+    fn per_die(&self) -> bool {
+        false
+    }
+
+    /// Whether the modifier is written as part of the dice, like the `!`
+    /// in `d6!`, rather than as a suffix after them
+    fn attached(&self) -> bool {
+        false
+    }
+
     fn pre_roll(&self, state: &mut RollRequest) -> Result<(), String> {
         let _ = state;
         Ok(())
@@ -56,6 +394,225 @@ pub trait DiceModifier: Debug {
     }
 }
 
+/// `r1`: cancels any die at or below the threshold and rolls a replacement
+#[derive(Debug)]
+pub struct Reroll {
+    pub below: i32,
+}
+
+impl DiceModifier for Reroll {
+    fn stage(&self) -> u32 {
+        0
+    }
+
+    fn per_die(&self) -> bool {
+        true
+    }
+
+    fn pre_roll(&self, state: &mut RollRequest) -> Result<(), String> {
+        if self.below >= state.spec.sides as i32 {
+            return Err(format!(
+                "Rerolling everything {} and under on a d{} would take forever!",
+                self.below, state.spec.sides
+            ));
+        }
+        Ok(())
+    }
+
+    fn post_roll(&self, state: &mut RollRequest) -> Result<(), String> {
+        for i in 0..state.iterations {
+            let mut pending = state.live_dice(i);
+            while let Some((key, value)) = pending.pop() {
+                if value <= self.below {
+                    state.cancellations.insert(key);
+                    let new_key = state.add_die(i)?;
+                    pending.push((new_key, state.roll_values[&new_key]));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Reroll {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "r{}", self.below)
+    }
+}
+
+/// `d6!`: every die that rolls its highest face adds another die
+#[derive(Debug)]
+pub struct Explode;
+
+impl DiceModifier for Explode {
+    fn stage(&self) -> u32 {
+        1
+    }
+
+    fn per_die(&self) -> bool {
+        true
+    }
+
+    fn attached(&self) -> bool {
+        true
+    }
+
+    fn pre_roll(&self, state: &mut RollRequest) -> Result<(), String> {
+        if state.spec.sides < 2 {
+            return Err("A d1 would explode forever!".into());
+        }
+        Ok(())
+    }
+
+    fn post_roll(&self, state: &mut RollRequest) -> Result<(), String> {
+        let max = state.spec.sides as i32;
+        for i in 0..state.iterations {
+            let mut pending = state.live_dice(i);
+            while let Some((_, value)) = pending.pop() {
+                if value == max {
+                    let new_key = state.add_die(i)?;
+                    pending.push((new_key, state.roll_values[&new_key]));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Explode {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "!")
+    }
+}
+
+/// `kh3`, `kl1`, `dh1`, `dl1`: keeps or drops the highest or lowest dice
+#[derive(Debug)]
+pub struct KeepDrop {
+    pub keep: bool,
+    pub highest: bool,
+    pub count: u32,
+}
+
+impl DiceModifier for KeepDrop {
+    fn stage(&self) -> u32 {
+        2
+    }
+
+    fn post_roll(&self, state: &mut RollRequest) -> Result<(), String> {
+        for i in 0..state.iterations {
+            let mut dice = state.live_dice(i);
+            dice.sort_by_key(|&(key, value)| (value, key));
+            if self.highest {
+                dice.reverse();
+            }
+
+            let count = (self.count as usize).min(dice.len());
+            let cancelled = if self.keep {
+                &dice[count..]
+            } else {
+                &dice[..count]
+            };
+            state
+                .cancellations
+                .extend(cancelled.iter().map(|&(key, _)| key));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for KeepDrop {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "{}{}{}",
+            if self.keep { "k" } else { "d" },
+            if self.highest { "h" } else { "l" },
+            self.count
+        )
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Comparison {
+    Less,
+    LessEqual,
+    Equal,
+    GreaterEqual,
+    Greater,
+}
+
+impl Comparison {
+    pub fn parse(s: &str) -> Option<Comparison> {
+        match s {
+            "<" => Some(Comparison::Less),
+            "<=" => Some(Comparison::LessEqual),
+            "=" => Some(Comparison::Equal),
+            ">=" => Some(Comparison::GreaterEqual),
+            ">" => Some(Comparison::Greater),
+            _ => None,
+        }
+    }
+
+    pub fn matches(&self, value: i32, target: i32) -> bool {
+        match self {
+            Comparison::Less => value < target,
+            Comparison::LessEqual => value <= target,
+            Comparison::Equal => value == target,
+            Comparison::GreaterEqual => value >= target,
+            Comparison::Greater => value > target,
+        }
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(match self {
+            Comparison::Less => "<",
+            Comparison::LessEqual => "<=",
+            Comparison::Equal => "=",
+            Comparison::GreaterEqual => ">=",
+            Comparison::Greater => ">",
+        })
+    }
+}
+
+/// `>=5`: the total becomes the number of dice that meet the target
+#[derive(Debug)]
+pub struct CountSuccesses {
+    pub comparison: Comparison,
+    pub target: i32,
+}
+
+impl DiceModifier for CountSuccesses {
+    fn stage(&self) -> u32 {
+        3
+    }
+
+    fn per_die(&self) -> bool {
+        true
+    }
+
+    fn post_roll(&self, state: &mut RollRequest) -> Result<(), String> {
+        let mut successes = BTreeMap::new();
+        for i in 0..state.iterations {
+            let count = state
+                .live_dice(i)
+                .into_iter()
+                .filter(|&(_, value)| self.comparison.matches(value, self.target))
+                .count();
+            successes.insert(i, count as i32);
+        }
+        state.successes = Some(successes);
+        Ok(())
+    }
+}
+
+impl fmt::Display for CountSuccesses {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}{}", self.comparison, self.target)
+    }
+}
+
 pub trait Dice {
     fn roll(&mut self, sides: u32) -> i32;
 }
@@ -68,3 +625,260 @@ where
         self.gen_range(0, sides) as i32 + 1
     }
 }
+
+pub fn roll_dice(ctx: &Context, msg: &Message, cmd: &Command) {
+    let expr = match cmd {
+        Command::Dice(expr) => expr,
+        _ => return,
+    };
+
+    let seed = rng::new_seed();
+    let result = match expr.roll(&mut rng::seeded(seed)) {
+        Ok(result) => result,
+        Err(e) => {
+            let _ = msg.reply(&e);
+            return;
+        }
+    };
+
+    println!("{} rolled {} with seed {}", msg.author.id, expr, seed);
+    let state = ::state(ctx);
+    state.dice_rolls.record(rolls::Entry {
+        seed,
+        user: msg.author.id,
+        expression: expr.to_string(),
+    });
+    // The roll still stands, it just can't be replayed after a restart
+    if let Err(e) = state.save_dice_rolls() {
+        ::log(
+            ctx,
+            &format!("Couldn't save the roll with seed {}: {}", seed, e),
+        );
+    }
+
+    let reply = format!(
+        "\u{1F3B2} {}\n{}\n*seed {}*",
+        expr,
+        describe_roll(expr, &result),
+        seed
+    );
+    ::logres(ctx, msg.reply(&reply));
+}
+
+pub fn replay_dice(ctx: &Context, msg: &Message, cmd: &Command) {
+    let seed = match cmd {
+        Command::ReplayDice(seed) => *seed,
+        _ => return,
+    };
+
+    let state = ::state(ctx);
+    let entry = match state.dice_rolls.find(seed) {
+        Some(entry) => entry,
+        None => {
+            let _ = msg.reply("I don't remember a roll with that seed, sorry!");
+            return;
+        }
+    };
+
+    let expr = match grammar::parse_dice(msg.author.id, &entry.expression) {
+        Ok(expr) => expr,
+        Err(e) => {
+            ::log(
+                ctx,
+                &format!(
+                    "Couldn't parse logged dice `{}`:\n{:?}",
+                    entry.expression, e
+                ),
+            );
+            let _ = msg.reply("Oops, something went wrong :( Ask a mod about it~");
+            return;
+        }
+    };
+
+    let result = match expr.roll(&mut rng::seeded(seed)) {
+        Ok(result) => result,
+        Err(e) => {
+            let _ = msg.reply(&e);
+            return;
+        }
+    };
+
+    let reply = format!(
+        "\u{1F3B2} Replaying {}'s roll of {} with seed {}\n{}",
+        entry.user.mention(),
+        expr,
+        seed,
+        describe_roll(&expr, &result)
+    );
+    ::logres(ctx, msg.reply(&reply));
+}
+
+fn describe_roll(expr: &DiceExpression, result: &ExpressionRoll) -> String {
+    let mut lines = Vec::new();
+    if let [(_, TermResult::Roll(ref roll))] = result.terms[..] {
+        for (dice, total) in roll.results.iter().zip(&roll.totals) {
+            lines.push(format!(
+                "[{}] = **{}**",
+                format_dice(dice),
+                format_total(*total, roll.counts_successes)
+            ));
+        }
+    } else {
+        for (&(_, ref result), &(_, ref term)) in result.terms.iter().zip(&expr.terms) {
+            let (roll, term) = match (result, term) {
+                (TermResult::Roll(roll), DiceTerm::Roll(term)) => (roll, term),
+                _ => continue,
+            };
+            for (dice, total) in roll.results.iter().zip(&roll.totals) {
+                lines.push(format!(
+                    "{}: [{}] = {}",
+                    term,
+                    format_dice(dice),
+                    format_total(*total, roll.counts_successes)
+                ));
+            }
+        }
+        lines.push(format!("Total: **{}**", result.total));
+    }
+    lines.join("\n")
+}
+
+fn format_dice(dice: &[(i32, bool)]) -> String {
+    dice.iter()
+        .map(|&(value, cancelled)| {
+            if cancelled {
+                format!("~~{}~~", value)
+            } else {
+                value.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn format_total(total: i32, counts_successes: bool) -> String {
+    if !counts_successes {
+        total.to_string()
+    } else if total == 1 {
+        "1 success".to_string()
+    } else {
+        format!("{} successes", total)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(expr: &str) -> DiceExpression {
+        grammar::parse_dice(UserId(1), expr).unwrap()
+    }
+
+    /// Dice that land on the given values, in order
+    struct Fixed(Vec<i32>);
+
+    impl Dice for Fixed {
+        fn roll(&mut self, sides: u32) -> i32 {
+            let value = self.0.remove(0);
+            assert!(1 <= value && value <= sides as i32);
+            value
+        }
+    }
+
+    /// Rolls a single group of dice, returning each die as (value,
+    /// cancelled) and the total
+    fn roll(expr: &str, values: &[i32]) -> (Vec<(i32, bool)>, i32) {
+        let mut dice = Fixed(values.to_vec());
+        let result = parse(expr).roll(&mut dice).unwrap();
+        assert!(dice.0.is_empty(), "{} didn't roll every die", expr);
+        match result.terms[..] {
+            [(_, TermResult::Roll(ref roll))] => (roll.results[0].clone(), result.total),
+            _ => panic!("{} isn't one roll", expr),
+        }
+    }
+
+    #[test]
+    fn same_seed_same_roll() {
+        let expr = parse("4d6kh3 + 2d8! - 1d4r1 + 3");
+        let first = expr.roll(&mut rng::seeded(42)).unwrap();
+
+        // Replaying goes through the logged text, so do the same here
+        let replayed = parse(&expr.to_string());
+        let second = replayed.roll(&mut rng::seeded(42)).unwrap();
+
+        assert_eq!(first.total, second.total);
+        for (a, b) in first.terms.iter().zip(&second.terms) {
+            match (&a.1, &b.1) {
+                (TermResult::Roll(a), TermResult::Roll(b)) => assert_eq!(a.results, b.results),
+                (a, b) => assert_eq!(a.total(), b.total()),
+            }
+        }
+    }
+
+    #[test]
+    fn rerolls_low_dice() {
+        let (dice, total) = roll("3d6r2", &[1, 5, 2, 6, 3]);
+        assert_eq!(
+            dice,
+            vec![(1, true), (5, false), (2, true), (6, false), (3, false)]
+        );
+        assert_eq!(total, 14);
+    }
+
+    #[test]
+    fn explodes_on_the_highest_face() {
+        let (dice, total) = roll("2d6!", &[6, 2, 6, 1]);
+        assert_eq!(dice.len(), 4);
+        assert_eq!(total, 15);
+    }
+
+    #[test]
+    fn keeps_and_drops() {
+        assert_eq!(roll("4d6kh3", &[1, 4, 6, 3]).1, 13);
+        assert_eq!(roll("4d6k3", &[1, 4, 6, 3]).1, 13);
+        assert_eq!(roll("4d6dl1", &[1, 4, 6, 3]).1, 13);
+        assert_eq!(roll("4d6kl1", &[1, 4, 6, 3]).1, 1);
+        assert_eq!(roll("4d6dh1", &[1, 4, 6, 3]).1, 8);
+
+        let (dice, _) = roll("4d6kh3", &[1, 4, 6, 3]);
+        assert_eq!(dice[0], (1, true));
+    }
+
+    #[test]
+    fn counts_successes() {
+        let mut dice = Fixed(vec![7, 2, 10, 6, 8]);
+        let result = parse("5d10>=7").roll(&mut dice).unwrap();
+        assert_eq!(result.total, 3);
+        match result.terms[..] {
+            [(_, TermResult::Roll(ref roll))] => assert!(roll.counts_successes),
+            _ => panic!("not one roll"),
+        }
+    }
+
+    #[test]
+    fn modifiers_apply_in_stage_order() {
+        // The 1 is rerolled into a 5, then the 6 explodes into a 4, then
+        // the highest two of what's left are kept
+        let values = [1, 6, 3, 2, 5, 4];
+        assert_eq!(roll("4d6!kh2r1", &values).1, 11);
+        assert_eq!(roll("4d6!r1kh2", &values).1, 11);
+    }
+
+    #[test]
+    fn adds_and_subtracts_terms() {
+        let mut dice = Fixed(vec![2, 5, 4]);
+        let result = parse("2d6 - 1d4 + 3").roll(&mut dice).unwrap();
+        assert_eq!(result.total, 6);
+    }
+
+    #[test]
+    fn rejects_numbers_too_big_to_add_up() {
+        for expr in &["99999999999", "2d6 + 3000000000", "1d6 - 1000001"] {
+            match parse(expr).roll(&mut rng::seeded(1)) {
+                Err(e) => assert!(e.contains("too big"), "{}: {}", expr, e),
+                Ok(_) => panic!("{} rolled", expr),
+            }
+        }
+        assert!(parse("1000000").roll(&mut rng::seeded(1)).is_ok());
+    }
+}
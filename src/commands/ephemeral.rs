@@ -0,0 +1,361 @@
+use serenity::model::prelude::*;
+use serenity::prelude::*;
+
+use std::collections::BTreeMap;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use discord::{ApiError, DiscordApi, MessageRef, SerenityApi};
+use grammar::ast::Command;
+use state::ephemeral::Pending;
+use state::State;
+use StaffAlertData;
+
+/// How long messages last in the void, unless it's been given a time of its
+/// own
+pub const VOID_TTL: u64 = 6;
+
+/// Discord only bulk deletes messages younger than two weeks, and this
+/// leaves an hour to spare
+const BULK_MAX_AGE: i64 = 14 * 24 * 60 * 60 - 60 * 60;
+/// How many rate limits to wait out before leaving the rest for later
+const RATE_LIMIT_RETRIES: u32 = 3;
+
+/// How long messages in `channel` last, in seconds, or `None` if they stay
+pub fn ttl(
+    state: &State,
+    staff_alert: &StaffAlertData,
+    guild: GuildId,
+    channel: ChannelId,
+) -> Option<u64> {
+    state.guild(guild).ephemeral.ttl(channel).or_else(|| {
+        if channel == staff_alert.the_void {
+            Some(VOID_TTL)
+        } else {
+            None
+        }
+    })
+}
+
+/// Remembers to delete a message `ttl` seconds after it was `sent`. It gets
+/// saved along with everything else on the next `delete_due`.
+pub fn schedule(
+    state: &State,
+    guild: GuildId,
+    channel: ChannelId,
+    message: MessageId,
+    sent: i64,
+    ttl: u64,
+) {
+    state
+        .guild(guild)
+        .ephemeral
+        .schedule(message, channel, sent, sent + ttl as i64);
+}
+
+/// Deletes everything in `guild` that's due by `now`. Whatever is left over
+/// from before a restart gets picked up here too.
+///
+/// Messages go in bulk where Discord allows it, and one at a time where it
+/// doesn't. Rate limits get waited out a few times, and after that the rest
+/// stays queued for the next go. Anything that can't be deleted at all is
+/// logged and dropped.
+///
+/// Whatever was scheduled or deleted since last time gets saved in one go
+/// at the end, rather than once per message.
+pub fn delete_due(api: &DiscordApi, state: &State, guild: GuildId, now: i64) {
+    delete_all_due(api, state, guild, now);
+    if let Err(e) = state.save_pending_deletes(guild) {
+        eprintln!("Couldn't save the pending deletes: {}", e);
+    }
+}
+
+fn delete_all_due(api: &DiscordApi, state: &State, guild: GuildId, now: i64) {
+    let due = state.guild(guild).ephemeral.due(now);
+    if due.is_empty() {
+        return;
+    }
+
+    let mut by_channel = BTreeMap::<ChannelId, Vec<(MessageId, Pending)>>::new();
+    for (message, pending) in due {
+        by_channel
+            .entry(pending.channel)
+            .or_insert_with(Vec::new)
+            .push((message, pending));
+    }
+
+    for (channel, messages) in by_channel {
+        if delete_in(api, state, guild, channel, &messages, now).is_err() {
+            return;
+        }
+    }
+}
+
+/// Deletes `messages` from one channel, stopping early with `Err` if Discord
+/// keeps rate limiting us
+fn delete_in(
+    api: &DiscordApi,
+    state: &State,
+    guild: GuildId,
+    channel: ChannelId,
+    messages: &[(MessageId, Pending)],
+    now: i64,
+) -> Result<(), ()> {
+    let done = |messages: &[MessageId]| state.guild(guild).ephemeral.done(messages);
+
+    let (bulk, old): (Vec<_>, Vec<_>) = messages
+        .iter()
+        .partition(|&&(_, pending)| now - pending.sent < BULK_MAX_AGE);
+    let bulk = bulk
+        .iter()
+        .map(|&&(message, _)| message)
+        .collect::<Vec<_>>();
+    let old = old.iter().map(|&&(message, _)| message).collect::<Vec<_>>();
+
+    let mut failed = Vec::new();
+    let mut result = Ok(());
+    for chunk in bulk.chunks(100).chain(old.chunks(1)) {
+        match delete_chunk(api, channel, chunk) {
+            Ok(errors) => {
+                failed.extend(errors);
+                done(chunk);
+            }
+            Err(deleted) => {
+                done(&chunk[..deleted]);
+                result = Err(());
+                break;
+            }
+        }
+    }
+
+    if let Some(e) = failed.first() {
+        api.log(&format!(
+            "Couldn't delete {} messages in {}, giving up on them: {:?}",
+            failed.len(),
+            channel.mention(),
+            e
+        ));
+    }
+    result
+}
+
+/// Deletes up to 100 `messages` from one channel in one go, or one at a time
+/// if Discord won't bulk delete them, like when some are older than two
+/// weeks. `Ok` has the errors for any that couldn't be deleted at all, and
+/// `Err` says how many were deleted before Discord kept rate limiting us.
+pub fn delete_chunk(
+    api: &DiscordApi,
+    channel: ChannelId,
+    messages: &[MessageId],
+) -> Result<Vec<ApiError>, usize> {
+    let result = with_backoff(|| {
+        if messages.len() == 1 {
+            api.delete_message(channel, messages[0])
+        } else {
+            api.delete_messages(channel, messages)
+        }
+    });
+    match result {
+        Ok(()) => return Ok(Vec::new()),
+        Err(ApiError::RateLimited(_)) => return Err(0),
+        Err(e) if messages.len() == 1 => return Ok(vec![e]),
+        Err(_) => (),
+    }
+
+    let mut failed = Vec::new();
+    for (i, &message) in messages.iter().enumerate() {
+        match with_backoff(|| api.delete_message(channel, message)) {
+            Ok(()) => (),
+            Err(ApiError::RateLimited(_)) => return Err(i),
+            Err(e) => failed.push(e),
+        }
+    }
+    Ok(failed)
+}
+
+/// Runs `f` again whenever it gets rate limited, after waiting for the limit
+/// to reset
+fn with_backoff<F>(mut f: F) -> Result<(), ApiError>
+where
+    F: FnMut() -> Result<(), ApiError>,
+{
+    let mut retries = 0;
+    loop {
+        match f() {
+            Err(ApiError::RateLimited(reset)) if retries < RATE_LIMIT_RETRIES => {
+                let wait = reset
+                    .duration_since(SystemTime::now())
+                    .unwrap_or(Duration::from_secs(0));
+                thread::sleep(wait + Duration::from_secs(1));
+                retries += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+pub fn set_ephemeral(ctx: &Context, msg: &Message, cmd: &Command) {
+    set_ephemeral_with(&SerenityApi::new(ctx), &::state(ctx), &msg.into(), cmd);
+}
+
+pub fn set_ephemeral_with(api: &DiscordApi, state: &State, msg: &MessageRef, cmd: &Command) {
+    let (channel, ttl) = match cmd {
+        Command::SetEphemeral(channel, ttl) => (*channel, *ttl),
+        _ => return,
+    };
+
+    let guild = msg.guild.unwrap();
+    state.guild(guild).ephemeral.set_ttl(channel, ttl);
+    if let Err(e) = state.save_ephemeral_channel(guild, channel) {
+        api.log(&format!("Couldn't save the auto-delete settings: {}", e));
+        let _ = api.reply(msg, "Oops, something went wrong :( Ask a mod about it~");
+        return;
+    }
+
+    let _ = api.react(msg.channel, msg.id, "\u{1F44D}");
+}
+
+/// Something like "24 hours", in the biggest unit that fits exactly
+pub fn describe_ttl(ttl: u64) -> String {
+    let units = [(24 * 60 * 60, "day"), (60 * 60, "hour"), (60, "minute")];
+    let (n, unit) = units
+        .iter()
+        .find(|&&(secs, _)| ttl >= secs && ttl % secs == 0)
+        .map_or((ttl, "second"), |&(secs, unit)| (ttl / secs, unit));
+    format!("{} {}{}", n, unit, if n == 1 { "" } else { "s" })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use discord::fake::{self, FakeGuild};
+
+    const ADMIN: UserId = UserId(100);
+    const VENT: ChannelId = ChannelId(20);
+
+    fn setup() -> (FakeGuild, State) {
+        let guild = FakeGuild::new();
+        let state = State::in_memory();
+        state.guild(guild.id).set_staff_alert(fake::staff_alert());
+        (guild, state)
+    }
+
+    fn post(guild: &FakeGuild, state: &State, channel: ChannelId, time: &str) -> MessageId {
+        let msg = guild.post(channel, UserId(1), "hi", time);
+        let sent = fake::time(time).timestamp();
+        let ttl = ttl(state, &fake::staff_alert(), guild.id, channel).unwrap();
+        schedule(state, guild.id, channel, msg.id, sent, ttl);
+        msg.id
+    }
+
+    #[test]
+    fn channels_keep_their_own_time() {
+        let (guild, state) = setup();
+        let staff_alert = fake::staff_alert();
+        let msg = guild.post(staff_alert.admin_channel, ADMIN, "", "2018-03-01T00:00:00Z");
+        set_ephemeral_with(
+            &guild,
+            &state,
+            &msg,
+            &Command::SetEphemeral(VENT, Some(24 * 60 * 60)),
+        );
+
+        let start = fake::time("2018-03-01T12:00:00Z").timestamp();
+        post(&guild, &state, VENT, "2018-03-01T12:00:00Z");
+        post(&guild, &state, staff_alert.the_void, "2018-03-01T12:00:00Z");
+
+        delete_due(&guild, &state, guild.id, start + 60);
+        assert!(guild.contents(staff_alert.the_void).is_empty());
+        assert_eq!(guild.contents(VENT).len(), 1);
+
+        delete_due(&guild, &state, guild.id, start + 24 * 60 * 60);
+        assert!(guild.contents(VENT).is_empty());
+    }
+
+    #[test]
+    fn pending_deletes_survive_a_restart() {
+        let (guild, state) = setup();
+        state.guild(guild.id).ephemeral.set_ttl(VENT, Some(60));
+        post(&guild, &state, VENT, "2018-03-01T12:00:00Z");
+        let start = fake::time("2018-03-01T12:00:00Z").timestamp();
+        delete_due(&guild, &state, guild.id, start);
+
+        let reloaded = state.reload();
+        let later = fake::time("2018-03-01T13:00:00Z").timestamp();
+        delete_due(&guild, &reloaded, guild.id, later);
+
+        assert!(guild.contents(VENT).is_empty());
+        assert!(reloaded.guild(guild.id).ephemeral.due(later).is_empty());
+    }
+
+    #[test]
+    fn deletes_old_messages_one_at_a_time() {
+        let (guild, state) = setup();
+        state.guild(guild.id).ephemeral.set_ttl(VENT, Some(60));
+        for i in 0..150 {
+            let time = format!("2018-02-01T12:{:02}:{:02}Z", i / 60, i % 60);
+            post(&guild, &state, VENT, &time);
+        }
+        post(&guild, &state, VENT, "2018-03-01T11:00:00Z");
+        post(&guild, &state, VENT, "2018-03-01T11:00:01Z");
+
+        guild.set_time("2018-03-01T12:00:00Z");
+        let now = fake::time("2018-03-01T12:00:00Z").timestamp();
+        delete_due(&guild, &state, guild.id, now);
+
+        assert!(guild.contents(VENT).is_empty());
+        assert!(guild.logs().is_empty());
+    }
+
+    #[test]
+    fn waits_out_rate_limits() {
+        let (guild, state) = setup();
+        let staff_alert = fake::staff_alert();
+        post(&guild, &state, staff_alert.the_void, "2018-03-01T12:00:00Z");
+        post(&guild, &state, staff_alert.the_void, "2018-03-01T12:00:01Z");
+
+        guild.rate_limit(1);
+        let now = fake::time("2018-03-01T12:01:00Z").timestamp();
+        delete_due(&guild, &state, guild.id, now);
+
+        assert!(guild.contents(staff_alert.the_void).is_empty());
+    }
+
+    #[test]
+    fn keeps_the_rest_when_rate_limits_go_on() {
+        let (guild, state) = setup();
+        let staff_alert = fake::staff_alert();
+        post(&guild, &state, staff_alert.the_void, "2018-03-01T12:00:00Z");
+
+        guild.rate_limit(RATE_LIMIT_RETRIES + 1);
+        let now = fake::time("2018-03-01T12:01:00Z").timestamp();
+        delete_due(&guild, &state, guild.id, now);
+        assert_eq!(state.guild(guild.id).ephemeral.due(now).len(), 1);
+
+        delete_due(&guild, &state, guild.id, now);
+        assert!(guild.contents(staff_alert.the_void).is_empty());
+    }
+
+    #[test]
+    fn logs_what_it_cannot_delete() {
+        let (guild, state) = setup();
+        let gone = ChannelId(30);
+        state.guild(guild.id).ephemeral.set_ttl(gone, Some(60));
+        let sent = fake::time("2018-03-01T12:00:00Z").timestamp();
+        schedule(&state, guild.id, gone, MessageId(1), sent, 60);
+        schedule(&state, guild.id, gone, MessageId(2), sent, 60);
+
+        delete_due(&guild, &state, guild.id, sent + 60);
+
+        assert!(state.guild(guild.id).ephemeral.due(sent + 60).is_empty());
+        assert_eq!(guild.logs().len(), 1);
+        assert!(guild.logs()[0].starts_with("Couldn't delete 2 messages"));
+    }
+
+    #[test]
+    fn describes_times() {
+        assert_eq!(describe_ttl(24 * 60 * 60), "1 day");
+        assert_eq!(describe_ttl(36 * 60 * 60), "36 hours");
+        assert_eq!(describe_ttl(90), "90 seconds");
+    }
+}
@@ -0,0 +1,805 @@
+use chrono::Utc;
+use serenity::model::prelude::*;
+use serenity::prelude::*;
+
+use discord::{logres, ChannelMessage, DiscordApi, MessageRef, SerenityApi};
+use grammar::ast::Command;
+use state::feedback::{Digest, Status, Ticket, Unmask, DIGEST_INTERVAL, MAX_PER_HOUR};
+use state::State;
+use StaffAlertData;
+
+const REPLY: &str = "reply to feedback #";
+
+/// Splits "reply to feedback #42: thanks!" into the ticket number and the
+/// answer. Staff use this in the feedback log, and authors in DMs.
+pub fn parse_reply(content: &str) -> Option<(u32, &str)> {
+    let content = content.trim_left();
+    match content.get(..REPLY.len()) {
+        Some(start) if start.eq_ignore_ascii_case(REPLY) => (),
+        _ => return None,
+    }
+
+    let rest = &content[REPLY.len()..];
+    let colon = rest.find(':')?;
+    let number = rest[..colon].trim().parse().ok()?;
+    let text = rest[colon + 1..].trim();
+    if text.is_empty() {
+        None
+    } else {
+        Some((number, text))
+    }
+}
+
+/// Opens a ticket for something posted in the anonymous feedback channel.
+/// `tag` is the author's `name#1234`.
+pub fn submit(
+    api: &DiscordApi,
+    state: &State,
+    staff_alert: &StaffAlertData,
+    guild: GuildId,
+    msg: &ChannelMessage,
+    tag: &str,
+) {
+    let now = msg.timestamp.timestamp();
+    let feedback = &state.guild(guild).feedback;
+    if !feedback.allow(msg.author, now) {
+        let _ = api.dm(msg.author, SLOW_DOWN);
+        return;
+    }
+
+    let (category, content) = split_category(&msg.content);
+    let number = feedback.open(Ticket {
+        author: msg.author,
+        content: content.to_string(),
+        category: category.clone(),
+        status: Status::Open,
+        opened: now,
+    });
+    let _ = state.save_ticket(guild, number);
+
+    let data = format!(
+        "Feedback #{number}: {author} provided some anonymous feedback{about} - {time}\n\
+         \"{content}\"",
+        number = number,
+        author = author(state, guild, msg.author, tag, now),
+        about = category.map_or(String::new(), |c| format!(" about `{}`", c)),
+        content = content,
+        time = msg.timestamp.to_rfc2822(),
+    );
+    let _ = api.say(staff_alert.feedback_log, &data);
+
+    let mut thanks = format!(
+        "Thanks for the feedback! It's number #{number}. If the staff answer \
+         I'll pass it on here, and you can add to it any time with \
+         `reply to feedback #{number}: ...`",
+        number = number
+    );
+    if feedback.is_anonymous() {
+        thanks.push_str("\nThe staff won't see who you are, just a code that changes every week.");
+    }
+    let _ = api.dm(msg.author, &thanks);
+}
+
+/// Feedback that starts with something like `[events]` gets filed under that
+/// category
+fn split_category(content: &str) -> (Option<String>, &str) {
+    let content = content.trim();
+    if content.starts_with('[') {
+        if let Some(end) = content.find(']') {
+            let category = content[1..end].trim().to_lowercase();
+            if !category.is_empty() {
+                return (Some(category), content[end + 1..].trim());
+            }
+        }
+    }
+    (None, content)
+}
+
+const SLOW_DOWN: &str = "That's a lot of feedback for one hour! Give it a bit and try again~";
+
+/// How the author shows up in the feedback log. In anonymous mode that's a
+/// pseudonym, so staff can still tell when feedback comes from the same
+/// person.
+fn author(state: &State, guild: GuildId, author: UserId, tag: &str, now: i64) -> String {
+    let feedback = &state.guild(guild).feedback;
+    if feedback.is_anonymous() {
+        let pseudonym = feedback.pseudonym(author, now);
+        let _ = state.save_feedback_settings(guild);
+        format!("someone anonymous (`{}`)", pseudonym)
+    } else {
+        format!("{} (`{}`)", author.mention(), tag)
+    }
+}
+
+/// Passes a staff answer on to whoever left the feedback, without saying
+/// who answered
+pub fn staff_reply(
+    api: &DiscordApi,
+    state: &State,
+    guild: GuildId,
+    msg: &MessageRef,
+    number: u32,
+    text: &str,
+) {
+    let ticket = match state.guild(guild).feedback.get(number) {
+        Some(ticket) => ticket,
+        None => {
+            let _ = api.reply(msg, &format!("There's no feedback #{}", number));
+            return;
+        }
+    };
+
+    let result = api.dm(
+        ticket.author,
+        &format!(
+            "The staff answered your feedback #{number}:\n\"{text}\"\n\
+             You can reply with `reply to feedback #{number}: ...`",
+            number = number,
+            text = text
+        ),
+    );
+    match result {
+        Ok(_) => {
+            let _ = api.react(msg.channel, msg.id, "\u{1F44D}");
+        }
+        Err(e) => {
+            api.log(&format!("Couldn't answer feedback #{}: {:?}", number, e));
+            let _ = api.reply(
+                msg,
+                "I couldn't DM them :( They might have left or turned off DMs",
+            );
+        }
+    }
+}
+
+/// Threads a follow-up from the author under their ticket. Tickets are
+/// numbered per guild, so it goes to whichever one the author's ticket is in.
+pub fn author_reply(
+    api: &DiscordApi,
+    state: &State,
+    guilds: &[GuildId],
+    msg: &MessageRef,
+    tag: &str,
+    number: u32,
+    text: &str,
+) {
+    let guild = guilds.iter().cloned().find(|&guild| {
+        state
+            .guild(guild)
+            .feedback
+            .get(number)
+            .map_or(false, |ticket| ticket.author == msg.author)
+    });
+
+    let (guild, staff_alert) =
+        match guild.and_then(|g| state.guild(g).staff_alert().map(|s| (g, s))) {
+            Some(found) => found,
+            None => {
+                let _ = api.say(
+                    msg.channel,
+                    &format!("I couldn't find any feedback #{} from you", number),
+                );
+                return;
+            }
+        };
+
+    let now = Utc::now().timestamp();
+    if !state.guild(guild).feedback.allow(msg.author, now) {
+        let _ = api.say(msg.channel, SLOW_DOWN);
+        return;
+    }
+
+    let data = format!(
+        "Feedback #{number}: {author} followed up\n\"{text}\"",
+        number = number,
+        author = author(state, guild, msg.author, tag, now),
+        text = text,
+    );
+    match api.say(staff_alert.feedback_log, &data) {
+        Ok(_) => {
+            let _ = api.react(msg.channel, msg.id, "\u{1F44D}");
+        }
+        Err(e) => {
+            api.log(&format!("Couldn't log a follow-up to #{}: {:?}", number, e));
+            let _ = api.say(
+                msg.channel,
+                "Oops, something went wrong :( Try again later?",
+            );
+        }
+    }
+}
+
+pub fn set_anonymous(ctx: &Context, msg: &Message, cmd: &Command) {
+    set_anonymous_with(&SerenityApi::new(ctx), &::state(ctx), &msg.into(), cmd);
+}
+
+pub fn set_anonymous_with(api: &DiscordApi, state: &State, msg: &MessageRef, cmd: &Command) {
+    let anonymous = match cmd {
+        Command::SetAnonymousFeedback(anonymous) => *anonymous,
+        _ => return,
+    };
+
+    let guild = msg.guild.unwrap();
+    state.guild(guild).feedback.set_anonymous(anonymous);
+    if let Err(e) = state.save_feedback_settings(guild) {
+        api.log(&format!("Couldn't save the feedback settings: {}", e));
+        let _ = api.reply(msg, "Oops, something went wrong :( Ask a mod about it~");
+        return;
+    }
+
+    let _ = api.react(msg.channel, msg.id, "\u{1F44D}");
+}
+
+pub fn unmask(ctx: &Context, msg: &Message, cmd: &Command) {
+    let staff_alert = match msg.guild_id().and_then(|guild| ::staff_alert(ctx, guild)) {
+        Some(staff_alert) => staff_alert,
+        None => return,
+    };
+    unmask_with(
+        &SerenityApi::new(ctx),
+        &::state(ctx),
+        &staff_alert,
+        &msg.into(),
+        cmd,
+    );
+}
+
+/// Says who sent some feedback, once two different admins have asked. Both
+/// asking and unmasking go in the log, and who it was only goes to the admin
+/// channel.
+pub fn unmask_with(
+    api: &DiscordApi,
+    state: &State,
+    staff_alert: &StaffAlertData,
+    msg: &MessageRef,
+    cmd: &Command,
+) {
+    let number = match cmd {
+        Command::UnmaskFeedback(number) => *number,
+        _ => return,
+    };
+
+    let guild = msg.guild.unwrap();
+    match state
+        .guild(guild)
+        .feedback
+        .request_unmask(number, msg.author)
+    {
+        Unmask::NoSuchTicket => {
+            let _ = api.reply(msg, &format!("There's no feedback #{}", number));
+        }
+        Unmask::AlreadyRequested => {
+            let _ = api.reply(
+                msg,
+                "You already asked! It needs a different admin to confirm.",
+            );
+        }
+        Unmask::Requested => {
+            api.log(&format!(
+                "{} asked to unmask feedback #{}",
+                msg.author.mention(),
+                number
+            ));
+            let _ = api.reply(
+                msg,
+                &format!(
+                    "Okay. Another admin has to confirm with `unmask feedback #{}` \
+                     before I say who it was.",
+                    number
+                ),
+            );
+        }
+        Unmask::Unmasked {
+            author,
+            requested_by,
+        } => {
+            let _ = state.save_feedback_settings(guild);
+            api.log(&format!(
+                "Feedback #{} was unmasked by {} and {}",
+                number,
+                requested_by.mention(),
+                msg.author.mention()
+            ));
+            let _ = api.say(
+                staff_alert.admin_channel,
+                &format!(
+                    "Feedback #{} was sent by {} (unmasked by {} and {})",
+                    number,
+                    author.mention(),
+                    requested_by.mention(),
+                    msg.author.mention()
+                ),
+            );
+        }
+    }
+}
+
+/// How many tickets a list or digest shows before it stops
+const MAX_LISTED: usize = 20;
+
+/// One line about a ticket, with the start of what it says
+fn describe(number: u32, ticket: &Ticket) -> String {
+    let mut line = format!("#{}", number);
+    if let Some(ref category) = ticket.category {
+        line.push_str(&format!(" [{}]", category));
+    }
+    let mut preview: String = ticket.content.chars().take(80).collect();
+    if preview.len() < ticket.content.len() {
+        preview.push_str("...");
+    }
+    line.push_str(&format!(" {}: \"{}\"", ticket.status, preview));
+    line
+}
+
+fn push_tickets(buf: &mut String, tickets: &[(u32, Ticket)]) {
+    for &(number, ref ticket) in tickets.iter().take(MAX_LISTED) {
+        buf.push('\n');
+        buf.push_str(&describe(number, ticket));
+    }
+    if tickets.len() > MAX_LISTED {
+        buf.push_str(&format!("\n...and {} more", tickets.len() - MAX_LISTED));
+    }
+}
+
+/// Feedback is only for staff to read, so listing it anywhere else gets the
+/// command deleted and a nudge in the mod channel instead
+fn in_staff_channel(api: &DiscordApi, staff_alert: &StaffAlertData, msg: &MessageRef) -> bool {
+    if msg.channel == staff_alert.admin_channel || msg.channel == staff_alert.mod_channel {
+        return true;
+    }
+
+    logres(api, api.delete_message(msg.channel, msg.id));
+    logres(
+        api,
+        api.say(
+            staff_alert.mod_channel,
+            &format!("Psst, {}, do that in here ya goof", msg.author.mention()),
+        ),
+    );
+    false
+}
+
+pub fn list(ctx: &Context, msg: &Message, cmd: &Command) {
+    let staff_alert = match msg.guild_id().and_then(|guild| ::staff_alert(ctx, guild)) {
+        Some(staff_alert) => staff_alert,
+        None => return,
+    };
+    list_with(
+        &SerenityApi::new(ctx),
+        &::state(ctx),
+        &staff_alert,
+        &msg.into(),
+        cmd,
+    );
+}
+
+pub fn list_with(
+    api: &DiscordApi,
+    state: &State,
+    staff_alert: &StaffAlertData,
+    msg: &MessageRef,
+    cmd: &Command,
+) {
+    let (status, category) = match cmd {
+        Command::ListFeedback(status, category) => (*status, category.as_ref().map(|c| &**c)),
+        _ => return,
+    };
+    if !in_staff_channel(api, staff_alert, msg) {
+        return;
+    }
+
+    let tickets = state
+        .guild(msg.guild.unwrap())
+        .feedback
+        .list(status, category);
+    if tickets.is_empty() {
+        let _ = api.reply(msg, "There's no feedback like that~");
+        return;
+    }
+
+    let mut reply = String::from("Feedback:");
+    push_tickets(&mut reply, &tickets);
+    let _ = api.reply(msg, &reply);
+}
+
+pub fn update(ctx: &Context, msg: &Message, cmd: &Command) {
+    update_with(&SerenityApi::new(ctx), &::state(ctx), &msg.into(), cmd);
+}
+
+/// Changes the status or category of a ticket
+pub fn update_with(api: &DiscordApi, state: &State, msg: &MessageRef, cmd: &Command) {
+    let guild = msg.guild.unwrap();
+    let feedback = &state.guild(guild).feedback;
+    let (number, found) = match cmd {
+        Command::SetFeedbackStatus(number, status) => {
+            (*number, feedback.update(*number, |t| t.status = *status))
+        }
+        Command::SetFeedbackCategory(number, category) => (
+            *number,
+            feedback.update(*number, |t| t.category = Some(category.clone())),
+        ),
+        _ => return,
+    };
+
+    if !found {
+        let _ = api.reply(msg, &format!("There's no feedback #{}", number));
+        return;
+    }
+    if let Err(e) = state.save_ticket(guild, number) {
+        api.log(&format!("Couldn't save feedback #{}: {}", number, e));
+        let _ = api.reply(msg, "Oops, something went wrong :( Ask a mod about it~");
+        return;
+    }
+
+    let _ = api.react(msg.channel, msg.id, "\u{1F44D}");
+}
+
+/// Posts the weekly digest to the admin channel if it's time for one, so
+/// that feedback doesn't get lost in the log
+pub fn digest(
+    api: &DiscordApi,
+    state: &State,
+    staff_alert: &StaffAlertData,
+    guild: GuildId,
+    now: i64,
+) {
+    let feedback = &state.guild(guild).feedback;
+    // Starting the clock gets saved too, or every restart would start it over
+    let due = feedback.digest_due(now);
+    if due != Digest::NotDue {
+        if let Err(e) = state.save_feedback_settings(guild) {
+            api.log(&format!(
+                "Couldn't save when the last digest went out: {}",
+                e
+            ));
+        }
+    }
+    if due != Digest::Due {
+        return;
+    }
+
+    let all = feedback.list(None, None);
+    let new = all
+        .iter()
+        .filter(|&&(_, ref t)| now - t.opened < DIGEST_INTERVAL)
+        .count();
+    let count = |status| all.iter().filter(|&&(_, ref t)| t.status == status).count();
+
+    let mut digest = format!(
+        "Weekly feedback digest: {} new this week, {} open, {} acknowledged and {} resolved.",
+        new,
+        count(Status::Open),
+        count(Status::Acknowledged),
+        count(Status::Resolved),
+    );
+    let waiting = all
+        .into_iter()
+        .filter(|&(_, ref t)| t.status != Status::Resolved)
+        .collect::<Vec<_>>();
+    if waiting.is_empty() {
+        digest.push_str("\nNothing is waiting on the staff~");
+    } else {
+        digest.push_str("\nStill waiting on the staff:");
+        push_tickets(&mut digest, &waiting);
+    }
+    let _ = api.say(staff_alert.admin_channel, &digest);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use discord::fake::{self, FakeGuild};
+
+    const AUTHOR: UserId = UserId(100);
+    const STAFF: UserId = UserId(200);
+    const DM: ChannelId = ChannelId(1);
+
+    fn setup() -> (FakeGuild, State, StaffAlertData) {
+        let guild = FakeGuild::new();
+        guild.add_member(AUTHOR, &[RoleId(1)]);
+        let state = State::in_memory();
+        state.guild(guild.id).set_staff_alert(fake::staff_alert());
+        (guild, state, fake::staff_alert())
+    }
+
+    fn feedback(guild: &FakeGuild, state: &State, staff_alert: &StaffAlertData, content: &str) {
+        let msg = ChannelMessage {
+            id: MessageId(1),
+            author: AUTHOR,
+            author_tag: "someone#0001".into(),
+            content: content.into(),
+            timestamp: fake::time("2018-03-01T12:00:00Z"),
+            edited: None,
+            attachments: Vec::new(),
+            pinned: false,
+        };
+        submit(guild, state, staff_alert, guild.id, &msg, "someone#0001");
+    }
+
+    #[test]
+    fn parses_replies() {
+        assert_eq!(
+            parse_reply("Reply to feedback #42: thanks!"),
+            Some((42, "thanks!"))
+        );
+        assert_eq!(
+            parse_reply("reply to feedback # 7 :a: b"),
+            Some((7, "a: b"))
+        );
+        assert_eq!(parse_reply("reply to feedback #42:"), None);
+        assert_eq!(parse_reply("reply to feedback #x: hi"), None);
+        assert_eq!(parse_reply("please reply to feedback #1: hi"), None);
+    }
+
+    #[test]
+    fn numbers_feedback() {
+        let (guild, state, staff_alert) = setup();
+
+        feedback(&guild, &state, &staff_alert, "first");
+        feedback(&guild, &state, &staff_alert, "second");
+
+        let log = guild.contents(staff_alert.feedback_log);
+        assert!(log[0].starts_with("Feedback #1:"));
+        assert!(log[1].starts_with("Feedback #2:"));
+        assert!(log[1].ends_with("\"second\""));
+        assert!(guild.dms(AUTHOR)[1].contains("#2"));
+    }
+
+    #[test]
+    fn staff_replies_go_to_the_author_anonymously() {
+        let (guild, state, staff_alert) = setup();
+        feedback(&guild, &state, &staff_alert, "the bot is too cute");
+
+        let msg = guild.post(staff_alert.feedback_log, STAFF, "", "2018-03-01T13:00:00Z");
+        staff_reply(&guild, &state, guild.id, &msg, 1, "no such thing");
+
+        let dm = guild.dms(AUTHOR).pop().unwrap();
+        assert!(dm.contains("feedback #1:\n\"no such thing\""));
+        assert!(!dm.contains(&STAFF.mention()));
+        assert_eq!(guild.reactions(msg.id), vec!["\u{1F44D}"]);
+    }
+
+    #[test]
+    fn complains_about_unknown_tickets() {
+        let (guild, state, staff_alert) = setup();
+
+        let msg = guild.post(staff_alert.feedback_log, STAFF, "", "2018-03-01T13:00:00Z");
+        staff_reply(&guild, &state, guild.id, &msg, 5, "hello?");
+
+        assert!(guild
+            .last_message(staff_alert.feedback_log)
+            .unwrap()
+            .ends_with("There's no feedback #5"));
+    }
+
+    #[test]
+    fn follow_ups_are_threaded_under_the_ticket() {
+        let (guild, state, staff_alert) = setup();
+        feedback(&guild, &state, &staff_alert, "first");
+        feedback(&guild, &state, &staff_alert, "second");
+
+        let msg = MessageRef {
+            id: MessageId(2),
+            channel: DM,
+            guild: None,
+            author: AUTHOR,
+        };
+        author_reply(
+            &guild,
+            &state,
+            &[guild.id],
+            &msg,
+            "someone#0001",
+            1,
+            "also this",
+        );
+
+        assert!(guild
+            .last_message(staff_alert.feedback_log)
+            .unwrap()
+            .starts_with("Feedback #1:"));
+        assert_eq!(guild.reactions(msg.id), vec!["\u{1F44D}"]);
+    }
+
+    #[test]
+    fn only_the_author_can_follow_up() {
+        let (guild, state, staff_alert) = setup();
+        feedback(&guild, &state, &staff_alert, "first");
+
+        let msg = MessageRef {
+            id: MessageId(2),
+            channel: DM,
+            guild: None,
+            author: UserId(300),
+        };
+        author_reply(
+            &guild,
+            &state,
+            &[guild.id],
+            &msg,
+            "nosy#0002",
+            1,
+            "what's this",
+        );
+
+        assert_eq!(guild.contents(staff_alert.feedback_log).len(), 1);
+        assert!(guild
+            .last_message(DM)
+            .unwrap()
+            .contains("couldn't find any feedback #1"));
+    }
+
+    #[test]
+    fn anonymous_feedback_only_shows_a_pseudonym() {
+        let (guild, state, staff_alert) = setup();
+        let msg = guild.post(staff_alert.admin_channel, STAFF, "", "2018-03-01T11:00:00Z");
+        set_anonymous_with(&guild, &state, &msg, &Command::SetAnonymousFeedback(true));
+
+        feedback(&guild, &state, &staff_alert, "first");
+        feedback(&guild, &state, &staff_alert, "second");
+
+        let log = guild.contents(staff_alert.feedback_log);
+        assert!(!log[0].contains(&AUTHOR.mention()));
+        assert!(!log[0].contains("someone#0001"));
+        let pseudonym = |line: &str| line.split('`').nth(1).unwrap().to_string();
+        assert_eq!(pseudonym(&log[0]), pseudonym(&log[1]));
+    }
+
+    #[test]
+    fn limits_feedback_per_hour() {
+        let (guild, state, staff_alert) = setup();
+
+        for _ in 0..MAX_PER_HOUR + 1 {
+            feedback(&guild, &state, &staff_alert, "spam");
+        }
+
+        let log = guild.contents(staff_alert.feedback_log);
+        assert_eq!(log.len(), MAX_PER_HOUR);
+        assert_eq!(guild.dms(AUTHOR).pop().unwrap(), SLOW_DOWN);
+    }
+
+    #[test]
+    fn unmasking_takes_two_admins() {
+        let (guild, state, staff_alert) = setup();
+        feedback(&guild, &state, &staff_alert, "first");
+        let unmask = Command::UnmaskFeedback(1);
+
+        let msg = guild.post(staff_alert.admin_channel, STAFF, "", "2018-03-01T13:00:00Z");
+        unmask_with(&guild, &state, &staff_alert, &msg, &unmask);
+        unmask_with(&guild, &state, &staff_alert, &msg, &unmask);
+        assert!(guild
+            .contents(staff_alert.admin_channel)
+            .iter()
+            .all(|line| !line.contains(&AUTHOR.mention())));
+
+        let msg = guild.post(
+            staff_alert.admin_channel,
+            UserId(201),
+            "",
+            "2018-03-01T13:05:00Z",
+        );
+        unmask_with(&guild, &state, &staff_alert, &msg, &unmask);
+        let revealed = guild.last_message(staff_alert.admin_channel).unwrap();
+        assert!(revealed.contains(&AUTHOR.mention()));
+        assert!(revealed.contains(&STAFF.mention()));
+        assert!(guild.logs().iter().any(|line| line.contains("unmasked by")));
+    }
+
+    #[test]
+    fn files_feedback_under_a_category() {
+        let (guild, state, staff_alert) = setup();
+        feedback(&guild, &state, &staff_alert, "[Events] more game nights");
+
+        let ticket = state.guild(guild.id).feedback.get(1).unwrap();
+        assert_eq!(ticket.category, Some("events".to_string()));
+        assert_eq!(ticket.content, "more game nights");
+        assert!(guild
+            .last_message(staff_alert.feedback_log)
+            .unwrap()
+            .contains("about `events`"));
+    }
+
+    #[test]
+    fn staff_update_and_filter_tickets() {
+        let (guild, state, staff_alert) = setup();
+        feedback(&guild, &state, &staff_alert, "first");
+        feedback(&guild, &state, &staff_alert, "second");
+
+        let msg = guild.post(staff_alert.mod_channel, STAFF, "", "2018-03-01T13:00:00Z");
+        update_with(
+            &guild,
+            &state,
+            &msg,
+            &Command::SetFeedbackStatus(1, Status::Resolved),
+        );
+        update_with(
+            &guild,
+            &state,
+            &msg,
+            &Command::SetFeedbackCategory(2, "bugs".into()),
+        );
+        list_with(
+            &guild,
+            &state,
+            &staff_alert,
+            &msg,
+            &Command::ListFeedback(Some(Status::Open), Some("bugs".into())),
+        );
+
+        let reply = guild.last_message(staff_alert.mod_channel).unwrap();
+        assert!(reply.contains("#2 [bugs] open: \"second\""));
+        assert!(!reply.contains("#1"));
+        assert_eq!(
+            state.guild(guild.id).feedback.get(1).unwrap().status,
+            Status::Resolved
+        );
+    }
+
+    #[test]
+    fn only_lists_in_staff_channels() {
+        let (guild, state, staff_alert) = setup();
+        feedback(&guild, &state, &staff_alert, "a secret");
+
+        let general = ChannelId(20);
+        let msg = guild.post(general, STAFF, "", "2018-03-01T13:00:00Z");
+        list_with(
+            &guild,
+            &state,
+            &staff_alert,
+            &msg,
+            &Command::ListFeedback(None, None),
+        );
+
+        assert!(guild.contents(general).is_empty());
+        assert!(guild
+            .last_message(staff_alert.mod_channel)
+            .unwrap()
+            .contains("do that in here"));
+    }
+
+    #[test]
+    fn weekly_digest_lists_what_is_waiting() {
+        let (guild, state, staff_alert) = setup();
+        let start = fake::time("2018-03-01T00:00:00Z").timestamp();
+        digest(&guild, &state, &staff_alert, guild.id, start);
+
+        feedback(&guild, &state, &staff_alert, "first");
+        feedback(&guild, &state, &staff_alert, "second");
+        state
+            .guild(guild.id)
+            .feedback
+            .update(1, |t| t.status = Status::Resolved);
+
+        digest(&guild, &state, &staff_alert, guild.id, start + 60 * 60);
+        assert!(guild.last_message(staff_alert.admin_channel).is_none());
+
+        digest(
+            &guild,
+            &state,
+            &staff_alert,
+            guild.id,
+            start + DIGEST_INTERVAL,
+        );
+        let digest = guild.last_message(staff_alert.admin_channel).unwrap();
+        assert!(digest.contains("2 new this week, 1 open, 0 acknowledged and 1 resolved"));
+        assert!(digest.contains("#2 open"));
+        assert!(!digest.contains("#1 "));
+    }
+
+    #[test]
+    fn digest_clock_survives_a_restart() {
+        let (guild, state, staff_alert) = setup();
+        let start = fake::time("2018-03-01T00:00:00Z").timestamp();
+        digest(&guild, &state, &staff_alert, guild.id, start);
+
+        let state = state.reload();
+        digest(
+            &guild,
+            &state,
+            &staff_alert,
+            guild.id,
+            start + DIGEST_INTERVAL,
+        );
+        assert!(guild.last_message(staff_alert.admin_channel).is_some());
+    }
+}
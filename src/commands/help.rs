@@ -0,0 +1,148 @@
+use serenity::model::prelude::*;
+use serenity::prelude::*;
+
+use grammar::ast::Command;
+use grammar::examples::{example_command, EXAMPLES};
+
+/// What each help topic is about, in the order they're listed
+static TOPICS: &[(&str, &str)] = &[
+    (
+        "pronouns",
+        "Set your pronoun roles. The first pronouns you list are your primary \
+         ones, and any others need to come after them on the server's list.",
+    ),
+    (
+        "roles",
+        "Give yourself roles or take them away. You can list as many as you \
+         like, separated by commas or \"and\".",
+    ),
+    (
+        "posts",
+        "Find out how many posts someone has made, and where.",
+    ),
+    (
+        "convert",
+        "Convert hormone levels between units. I know about testosterone and estradiol.",
+    ),
+    (
+        "dice",
+        "Roll dice or work out the odds. After the dice, `k3`/`kl3` keeps the \
+         highest/lowest three, `dh`/`dl` drops them, `r2` rerolls anything \
+         below 2, `!` makes dice explode, and `>=5` counts successes. Every \
+         roll has a seed so it can be checked later.",
+    ),
+    (
+        "purge",
+        "Delete messages from a channel between two times, between two message \
+         links, or from the last so many hours. It can also stick to messages \
+         from certain people or matching a pattern, and take pinned messages \
+         too. This has to be done in the admin channel with a fresh challenge \
+         code, saves a transcript of what it found, and asks for confirmation \
+         before anything is deleted. If I restart partway through deleting, \
+         I'll carry on where I left off. Challenge codes only work for the \
+         admin who asked for them, run out after five minutes unless that's \
+         changed, and ping everyone, a role or nobody. With the two person \
+         rule on, a second admin has to confirm a purge before it goes ahead, \
+         and two admins have to ask before it goes off.",
+    ),
+    (
+        "settings",
+        "Change where staff get told about things: the admin channel, mod \
+         channel, front door, void, anon feedback channel and feedback log, \
+         and the mod call role. Any channel can also have its messages \
+         deleted after a while, like the void.",
+    ),
+    (
+        "feedback",
+        "Anything posted in the anon feedback channel goes to the staff as a \
+         numbered ticket. Starting it with something like `[events]` files it \
+         under that category. Staff can list tickets and mark them as open, \
+         acknowledged or resolved, and the admin channel gets a digest every \
+         week. In anonymous mode staff only see a code that changes every \
+         week, and it takes two admins to find out who sent something.",
+    ),
+];
+
+/// How many examples each topic gets in the overview
+const OVERVIEW_EXAMPLES: usize = 2;
+
+pub fn help(ctx: &Context, msg: &Message, cmd: &Command) {
+    if let Some(member) = msg.member() {
+        help_for(ctx, msg, cmd, &member);
+    }
+}
+
+/// Only shows what `member` can run, which matters in DMs where the message
+/// doesn't come from a member
+pub fn help_for(ctx: &Context, msg: &Message, cmd: &Command, member: &Member) {
+    let topic = match cmd {
+        Command::Help(topic) => topic,
+        _ => return,
+    };
+
+    let available = TOPICS
+        .iter()
+        .map(|&(topic, about)| (topic, about, examples(topic, msg.author.id, member)))
+        .filter(|&(_, _, ref examples)| !examples.is_empty())
+        .collect::<Vec<_>>();
+
+    let reply = match topic {
+        None => {
+            let mut reply = match ::bot_prefix(ctx) {
+                Some(prefix) => {
+                    format!("Mention me or start with `{}` and say things like:", prefix)
+                }
+                None => String::from("Mention me and say things like:"),
+            };
+            for &(topic, _, ref examples) in &available {
+                reply.push_str(&format!("\n**{}**", topic));
+                for example in examples.iter().take(OVERVIEW_EXAMPLES) {
+                    reply.push_str(&format!("\n`{}`", example));
+                }
+            }
+            reply.push_str("\nAsk me for `help` and one of those topics to find out more~");
+            reply
+        }
+        Some(topic) => match available.iter().find(|&&(t, _, _)| same_topic(t, topic)) {
+            Some(&(_, about, ref examples)) => {
+                let mut reply = String::from(about);
+                for example in examples {
+                    reply.push_str(&format!("\n`{}`", example));
+                }
+                reply
+            }
+            None => {
+                let topics = available
+                    .iter()
+                    .map(|&(t, _, _)| t)
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                format!(
+                    "I don't have any help about `{}`, sorry! I can tell you about {}",
+                    topic, topics
+                )
+            }
+        },
+    };
+
+    let _ = msg.reply(&reply);
+}
+
+/// The examples for a topic that the user is allowed to run
+fn examples(topic: &str, user: UserId, member: &Member) -> Vec<&'static str> {
+    EXAMPLES
+        .iter()
+        .filter(|&&(t, _)| t == topic)
+        .map(|&(_, phrase)| phrase)
+        .filter(|phrase| {
+            example_command(phrase, user)
+                .map(|cmd| cmd.is_authorized(user, member))
+                .unwrap_or(false)
+        })
+        .collect()
+}
+
+/// Lets "role" find "roles" and so on
+fn same_topic(topic: &str, asked: &str) -> bool {
+    topic.trim_right_matches('s') == asked.trim_right_matches('s')
+}
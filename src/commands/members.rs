@@ -0,0 +1,131 @@
+use serenity::model::prelude::*;
+
+use std::fmt::Write;
+
+use discord::DiscordApi;
+use state::State;
+use StaffAlertData;
+
+pub fn member_joined(
+    api: &DiscordApi,
+    state: &State,
+    staff_alert: &StaffAlertData,
+    guild: GuildId,
+    user: UserId,
+) {
+    let mut message = format!(
+        "Hey {modcall}, {user} just joined! Check the {frontdoor}~",
+        modcall = staff_alert.mod_call.mention(),
+        frontdoor = staff_alert.front_door.mention(),
+        user = user.mention(),
+    );
+
+    let times = state.guild(guild).leave_counts.get(user);
+    if times > 0 {
+        let _ = write!(
+            &mut message,
+            " (they're back for the {}{} time)",
+            times,
+            cardinality(times)
+        );
+    }
+
+    let _ = api.say(staff_alert.mod_channel, &message);
+}
+
+/// `tag` is the user's `name#1234`, since they can't be looked up any more
+pub fn member_left(api: &DiscordApi, state: &State, guild: GuildId, user: UserId, tag: &str) {
+    let times = state.guild(guild).leave_counts.increment(user);
+    let _ = state.save_leave_count(guild, user);
+
+    api.log(&format!(
+        "{} ({}) left the server ({}{} time)",
+        user.mention(),
+        tag,
+        times,
+        cardinality(times)
+    ));
+}
+
+fn cardinality(i: u32) -> &'static str {
+    match ((i / 10) % 10, i % 10) {
+        (1, _) => "th",
+        (_, 1) => "st",
+        (_, 2) => "nd",
+        (_, 3) => "rd",
+        (_, _) => "th",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use discord::fake::{self, FakeGuild};
+
+    const MEMBER: UserId = UserId(100);
+
+    #[test]
+    fn tells_the_mods_about_new_members() {
+        let guild = FakeGuild::new();
+        let state = State::in_memory();
+        let staff_alert = fake::staff_alert();
+
+        member_joined(&guild, &state, &staff_alert, guild.id, MEMBER);
+
+        let message = guild.last_message(staff_alert.mod_channel).unwrap();
+        assert!(message.contains("just joined"));
+        assert!(!message.contains("they're back"));
+    }
+
+    #[test]
+    fn counts_how_often_members_leave() {
+        let guild = FakeGuild::new();
+        let state = State::in_memory();
+        let staff_alert = fake::staff_alert();
+
+        member_left(&guild, &state, guild.id, MEMBER, "someone#0001");
+        member_left(&guild, &state, guild.id, MEMBER, "someone#0001");
+        member_joined(&guild, &state, &staff_alert, guild.id, MEMBER);
+
+        let logs = guild.logs();
+        assert!(logs[0].ends_with("left the server (1st time)"));
+        assert!(logs[1].ends_with("left the server (2nd time)"));
+        assert!(guild
+            .last_message(staff_alert.mod_channel)
+            .unwrap()
+            .ends_with("(they're back for the 2nd time)"));
+    }
+
+    #[test]
+    fn leave_counts_are_kept_per_guild() {
+        let guild = FakeGuild::new();
+        let state = State::in_memory();
+        let staff_alert = fake::staff_alert();
+
+        member_left(&guild, &state, GuildId(2), MEMBER, "someone#0001");
+        member_joined(&guild, &state, &staff_alert, guild.id, MEMBER);
+
+        assert!(!guild
+            .last_message(staff_alert.mod_channel)
+            .unwrap()
+            .contains("they're back"));
+    }
+
+    #[test]
+    fn cardinalities() {
+        let expected = [
+            (1, "st"),
+            (2, "nd"),
+            (3, "rd"),
+            (4, "th"),
+            (11, "th"),
+            (12, "th"),
+            (13, "th"),
+            (21, "st"),
+            (112, "th"),
+        ];
+        for &(i, suffix) in &expected {
+            assert_eq!(cardinality(i), suffix);
+        }
+    }
+}
@@ -1,6 +1,13 @@
 pub mod convert;
 pub mod dice;
+pub mod ephemeral;
+pub mod feedback;
+pub mod help;
+pub mod members;
 pub mod niceties;
+pub mod odds;
+pub mod posts;
 pub mod pronouns;
 pub mod purge;
 pub mod roles;
+pub mod settings;
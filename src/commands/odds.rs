@@ -0,0 +1,422 @@
+use serenity::model::prelude::*;
+use serenity::prelude::*;
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use commands::dice::{self, Comparison, Dice, DiceExpression, DiceRoll, DiceSpecifier, DiceTerm};
+use grammar::ast::Command;
+
+/// Paths through the dice less likely than this are written off instead of
+/// followed, otherwise exploding dice would never finish
+const PRUNE_BELOW: f64 = 1e-10;
+const MAX_OUTCOMES: u32 = 1_000_000;
+const MAX_CONVOLUTION: usize = 50_000_000;
+const HISTOGRAM_ROWS: i32 = 20;
+const HISTOGRAM_WIDTH: f64 = 20.0;
+/// The histogram leaves off tails with less than this much chance in them
+const HISTOGRAM_CUTOFF: f64 = 0.00005;
+
+pub struct Distribution {
+    /// The smallest possible total; `probs[i]` is the chance of `min + i`
+    pub min: i32,
+    pub probs: Vec<f64>,
+    /// Chance of outcomes that were too unlikely to work out
+    pub lost: f64,
+}
+
+impl Distribution {
+    pub fn constant(value: i32) -> Distribution {
+        Distribution {
+            min: value,
+            probs: vec![1.0],
+            lost: 0.0,
+        }
+    }
+
+    fn from_outcomes(outcomes: BTreeMap<i32, f64>, lost: f64) -> Distribution {
+        let min = outcomes.keys().next().cloned().unwrap_or(0);
+        let max = outcomes.keys().next_back().cloned().unwrap_or(0);
+        let mut probs = vec![0.0; (max - min) as usize + 1];
+        for (value, p) in outcomes {
+            probs[(value - min) as usize] += p;
+        }
+        Distribution { min, probs, lost }
+    }
+
+    pub fn max(&self) -> i32 {
+        self.min + self.probs.len() as i32 - 1
+    }
+
+    pub fn negate(&self) -> Distribution {
+        Distribution {
+            min: -self.max(),
+            probs: self.probs.iter().rev().cloned().collect(),
+            lost: self.lost,
+        }
+    }
+
+    /// Adds one fair die to the distribution
+    pub fn add_die(&self, sides: u32) -> Distribution {
+        let sides = sides as usize;
+        let len = self.probs.len() + sides - 1;
+        let mut probs = Vec::with_capacity(len);
+        let mut window = 0.0;
+        for i in 0..len {
+            if i < self.probs.len() {
+                window += self.probs[i];
+            }
+            if i >= sides {
+                window -= self.probs[i - sides];
+            }
+            probs.push((window / sides as f64).max(0.0));
+        }
+
+        Distribution {
+            min: self.min + 1,
+            probs,
+            lost: self.lost,
+        }
+    }
+
+    /// The distribution of the sum of both
+    pub fn add(&self, other: &Distribution) -> Result<Distribution, String> {
+        if self.probs.len() * other.probs.len() > MAX_CONVOLUTION {
+            return Err(too_complicated());
+        }
+
+        let mut probs = vec![0.0; self.probs.len() + other.probs.len() - 1];
+        for (i, &a) in self.probs.iter().enumerate() {
+            if a == 0.0 {
+                continue;
+            }
+            for (j, &b) in other.probs.iter().enumerate() {
+                probs[i + j] += a * b;
+            }
+        }
+
+        Ok(Distribution {
+            min: self.min + other.min,
+            probs,
+            lost: self.lost + other.lost - self.lost * other.lost,
+        })
+    }
+
+    pub fn mean(&self) -> f64 {
+        let (sum, weight) = self
+            .outcomes()
+            .fold((0.0, 0.0), |(sum, weight), (value, p)| {
+                (sum + value as f64 * p, weight + p)
+            });
+        if weight > 0.0 {
+            sum / weight
+        } else {
+            0.0
+        }
+    }
+
+    pub fn chance(&self, comparison: Comparison, target: i32) -> f64 {
+        self.outcomes()
+            .filter(|&(value, _)| comparison.matches(value, target))
+            .fold(0.0, |sum, (_, p)| sum + p)
+    }
+
+    pub fn outcomes<'a>(&'a self) -> impl DoubleEndedIterator<Item = (i32, f64)> + 'a {
+        self.probs
+            .iter()
+            .enumerate()
+            .map(move |(i, &p)| (self.min + i as i32, p))
+    }
+}
+
+pub fn distribution(expr: &DiceExpression) -> Result<Distribution, String> {
+    expr.check_limits()?;
+
+    let mut total = Distribution::constant(0);
+    for &(sign, ref term) in &expr.terms {
+        let dist = match term {
+            DiceTerm::Roll(roll) => roll_distribution(roll)?,
+            DiceTerm::Constant(value) => Distribution::constant(*value),
+        };
+        let dist = if sign < 0 { dist.negate() } else { dist };
+        total = total.add(&dist)?;
+    }
+    Ok(total)
+}
+
+fn roll_distribution(roll: &DiceRoll) -> Result<Distribution, String> {
+    let spec = roll.roll_spec;
+
+    if roll.modifiers.is_empty() {
+        spec.check_limits()?;
+        let mut dist = Distribution::constant(0);
+        for _ in 0..spec.num {
+            dist = dist.add_die(spec.sides);
+        }
+        return Ok(dist);
+    }
+
+    if roll.modifiers.iter().all(|m| m.per_die()) {
+        spec.check_limits()?;
+        let single = enumerate(roll, DiceSpecifier { num: 1, ..spec })?;
+        let mut dist = Distribution::constant(0);
+        for _ in 0..spec.num {
+            dist = dist.add(&single)?;
+        }
+        return Ok(dist);
+    }
+
+    enumerate(roll, spec)
+}
+
+/// Runs the real roll once for every way the dice could land
+fn enumerate(roll: &DiceRoll, spec: DiceSpecifier) -> Result<Distribution, String> {
+    let mut walker = PathWalker::default();
+    let mut outcomes = BTreeMap::new();
+    let mut lost = 0.0;
+
+    for _ in 0..MAX_OUTCOMES {
+        walker.restart();
+        let result = roll.roll_as(spec, &mut walker);
+        match result {
+            _ if walker.pruned => lost += walker.probability,
+            Ok(result) => {
+                let total = result.totals.iter().sum();
+                *outcomes.entry(total).or_insert(0.0) += walker.probability;
+            }
+            Err(e) => return Err(e),
+        }
+
+        if !walker.advance() {
+            return Ok(Distribution::from_outcomes(outcomes, lost));
+        }
+    }
+
+    Err(too_complicated())
+}
+
+/// A `Dice` that walks every possible sequence of rolls, one per run
+#[derive(Default)]
+struct PathWalker {
+    /// The face each die landed on along the current path, and how many
+    /// faces we're branching over for it
+    path: Vec<(u32, u32)>,
+    pos: usize,
+    probability: f64,
+    pruned: bool,
+}
+
+impl PathWalker {
+    fn restart(&mut self) {
+        self.pos = 0;
+        self.probability = 1.0;
+        self.pruned = false;
+    }
+
+    fn advance(&mut self) -> bool {
+        self.path.truncate(self.pos);
+        while let Some((face, branches)) = self.path.pop() {
+            if face < branches {
+                self.path.push((face + 1, branches));
+                return true;
+            }
+        }
+        false
+    }
+}
+
+impl Dice for PathWalker {
+    fn roll(&mut self, sides: u32) -> i32 {
+        if self.pos == self.path.len() {
+            // Once a path gets unlikely enough, stop branching and write
+            // off the rest of it as one outcome
+            let branches = if self.pruned || self.probability < PRUNE_BELOW {
+                1
+            } else {
+                sides
+            };
+            self.path.push((1, branches));
+        }
+
+        let (face, branches) = self.path[self.pos];
+        self.pos += 1;
+        if branches < sides {
+            self.pruned = true;
+        } else {
+            self.probability /= sides as f64;
+        }
+        face as i32
+    }
+}
+
+fn too_complicated() -> String {
+    "That's too complicated for me to work out exactly, sorry! Try fewer dice?".into()
+}
+
+pub fn dice_odds(ctx: &Context, msg: &Message, cmd: &Command) {
+    let (expr, target) = match cmd {
+        Command::DiceOdds(expr, target) => (expr, *target),
+        _ => return,
+    };
+
+    let checked = match target {
+        Some((_, target)) => dice::check_number(target),
+        None => Ok(()),
+    };
+    let dist = match checked.and_then(|_| distribution(expr)) {
+        Ok(dist) => dist,
+        Err(e) => {
+            let _ = msg.reply(&e);
+            return;
+        }
+    };
+
+    let mut reply = match target {
+        Some((comparison, target)) => format!(
+            "\u{1F3B2} The odds of {} {} {} are **{:.2}%**",
+            expr,
+            comparison,
+            target,
+            dist.chance(comparison, target) * 100.0
+        ),
+        None => format!("\u{1F3B2} Here are the odds for {}", expr),
+    };
+    let _ = write!(reply, "\nAverage: {:.2}", dist.mean());
+    if dist.lost > 1e-6 {
+        let _ = write!(
+            reply,
+            " (ignoring a {:.4}% chance of dice that keep going forever)",
+            dist.lost * 100.0
+        );
+    }
+
+    reply.push_str("\n```");
+    write_histogram(&mut reply, &dist);
+    reply.push_str("\n```");
+
+    ::logres(ctx, msg.reply(&reply));
+}
+
+fn write_histogram(buf: &mut String, dist: &Distribution) {
+    let (low, high) = match (
+        tail_start(dist.outcomes()),
+        tail_start(dist.outcomes().rev()),
+    ) {
+        (Some(low), Some(high)) => (low, high),
+        _ => return,
+    };
+
+    let range = high as i64 - low as i64 + 1;
+    let width = ((range + HISTOGRAM_ROWS as i64 - 1) / HISTOGRAM_ROWS as i64) as i32;
+
+    let mut rows = Vec::new();
+    let mut start = low;
+    loop {
+        let end = start.saturating_add(width - 1).min(high);
+        let p: f64 = dist
+            .outcomes()
+            .filter(|&(v, _)| v >= start && v <= end)
+            .map(|(_, p)| p)
+            .sum();
+        let label = if start == end {
+            start.to_string()
+        } else {
+            format!("{}-{}", start, end)
+        };
+        rows.push((label, p));
+
+        if end >= high {
+            break;
+        }
+        start = end + 1;
+    }
+
+    let label_width = rows.iter().map(|&(ref l, _)| l.len()).max().unwrap_or(0);
+    let tallest = rows.iter().map(|&(_, p)| p).fold(0.0, f64::max);
+    for (label, p) in rows {
+        let bar = (p / tallest * HISTOGRAM_WIDTH).round() as usize;
+        let _ = write!(
+            buf,
+            "\n{:>lw$} | {:<bw$} {:.2}%",
+            label,
+            "\u{2588}".repeat(bar),
+            p * 100.0,
+            lw = label_width,
+            bw = HISTOGRAM_WIDTH as usize,
+        );
+    }
+}
+
+/// The first outcome where the tail so far has stopped being negligible
+fn tail_start<I>(outcomes: I) -> Option<i32>
+where
+    I: Iterator<Item = (i32, f64)>,
+{
+    let mut tail = 0.0;
+    for (value, p) in outcomes {
+        tail += p;
+        if tail >= HISTOGRAM_CUTOFF {
+            return Some(value);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use grammar;
+
+    fn odds(expr: &str) -> Distribution {
+        distribution(&grammar::parse_dice(UserId(1), expr).unwrap()).unwrap()
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} isn't {}", a, b);
+    }
+
+    #[test]
+    fn two_dice_make_a_triangle() {
+        let dist = odds("2d6");
+        assert_eq!((dist.min, dist.max()), (2, 12));
+        for (value, p) in dist.outcomes() {
+            let ways = 6 - (value - 7).abs();
+            assert_close(p, ways as f64 / 36.0);
+        }
+        assert_close(dist.mean(), 7.0);
+        assert_close(dist.chance(Comparison::GreaterEqual, 10), 6.0 / 36.0);
+    }
+
+    #[test]
+    fn keeping_the_best_three_of_four() {
+        let dist = odds("4d6kh3");
+        assert_eq!((dist.min, dist.max()), (3, 18));
+        assert_close(dist.mean(), 15869.0 / 1296.0);
+        assert_close(dist.chance(Comparison::Equal, 18), 21.0 / 1296.0);
+    }
+
+    #[test]
+    fn counting_successes_is_binomial() {
+        // Each die has a 1 in 3 chance of a 5 or 6
+        let dist = odds("3d6>=5");
+        let expected = [8.0, 12.0, 6.0, 1.0];
+        for (value, p) in dist.outcomes() {
+            assert_close(p, expected[value as usize] / 27.0);
+        }
+    }
+
+    #[test]
+    fn exploding_dice_write_off_only_a_sliver() {
+        let dist = odds("1d6!");
+        assert!(dist.lost < 1e-6);
+        assert!((dist.mean() - 4.2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn constants_shift_the_distribution() {
+        let dist = odds("1d4 - 1d4 + 2");
+        assert_eq!((dist.min, dist.max()), (-1, 5));
+        assert_close(dist.mean(), 2.0);
+        assert_close(dist.chance(Comparison::Equal, 2), 4.0 / 16.0);
+    }
+}
@@ -0,0 +1,59 @@
+use serenity::model::prelude::*;
+use serenity::prelude::*;
+
+use std::fmt::Write;
+
+use grammar::ast::Command;
+
+const MAX_CHANNELS: usize = 15;
+
+pub fn how_many_posts(ctx: &Context, msg: &Message, cmd: &Command) {
+    let target = match cmd {
+        Command::HowManyPosts(target) => *target,
+        _ => return,
+    };
+
+    let state = ::state(ctx).guild(msg.guild_id().unwrap());
+    let mut channels = state
+        .post_counts
+        .get(target)
+        .into_iter()
+        .collect::<Vec<_>>();
+    let total: u32 = channels.iter().map(|&(_, count)| count).sum();
+
+    let (who, has) = if target == msg.author.id {
+        ("You".to_string(), "have")
+    } else {
+        (target.mention(), "has")
+    };
+
+    if total == 0 {
+        let _ = msg.reply(&format!(
+            "{} {}n't posted anything since I started counting!",
+            who, has
+        ));
+        return;
+    }
+
+    channels.sort_by(|a, b| b.1.cmp(&a.1));
+
+    let mut reply = format!(
+        "{} {} made {} post{} since I started counting:",
+        who,
+        has,
+        total,
+        if total == 1 { "" } else { "s" }
+    );
+    for &(channel, count) in channels.iter().take(MAX_CHANNELS) {
+        let _ = write!(reply, "\n{}: {}", channel.mention(), count);
+    }
+    if channels.len() > MAX_CHANNELS {
+        let _ = write!(
+            reply,
+            "\n...and {} more channels",
+            channels.len() - MAX_CHANNELS
+        );
+    }
+
+    ::logres(ctx, msg.reply(&reply));
+}
@@ -3,7 +3,9 @@ use serenity::prelude::*;
 
 use std::collections::HashSet;
 
+use discord::{DiscordApi, MessageRef, SerenityApi};
 use grammar::ast::Command;
+use state::State;
 
 pub fn scan_pronouns(ctx: &Context, msg: &Message, cmd: &Command) {
     let (high, low) = match cmd {
@@ -21,11 +23,12 @@ pub fn scan_pronouns(ctx: &Context, msg: &Message, cmd: &Command) {
     };
 
     let state = ::state(ctx);
-    let result = state.pronouns.rescan(&guild, high, low);
+    let guild_state = state.guild(guild.id);
+    let result = guild_state.pronouns.rescan(&guild, high, low);
     match result {
         Ok(_) => {
-            let _ = state.save();
-            let taglist = state
+            let _ = state.save_guild(guild.id);
+            let taglist = guild_state
                 .pronouns
                 .all_role_tags()
                 .collect::<Vec<_>>()
@@ -40,36 +43,35 @@ pub fn scan_pronouns(ctx: &Context, msg: &Message, cmd: &Command) {
 }
 
 pub fn set_pronouns(ctx: &Context, msg: &Message, cmd: &mut Command) {
+    set_pronouns_with(&SerenityApi::new(ctx), &::state(ctx), &msg.into(), cmd);
+}
+
+pub fn set_pronouns_with(api: &DiscordApi, state: &State, msg: &MessageRef, cmd: &mut Command) {
     let (target, pronouns) = match cmd {
         Command::SetPronouns { target, pronouns } => (*target, pronouns),
         _ => return,
     };
 
-    let mut target = match msg.guild_id().unwrap().member(target) {
-        Ok(target) => target,
-        Err(e) => {
-            ::log(
-                ctx,
-                &format!(
-                    "Wowzers! Error setting {}'s pronouns:\n{:?}",
-                    msg.author.mention(),
-                    e
-                ),
-            );
-            let _ = msg.reply("Oops, something went wrong :( Ask a mod about it~");
-            return;
-        }
-    };
+    let guild = msg.guild.unwrap();
+    if let Err(e) = api.member_roles(guild, target) {
+        api.log(&format!(
+            "Wowzers! Error setting {}'s pronouns:\n{:?}",
+            msg.author.mention(),
+            e
+        ));
+        let _ = api.reply(msg, "Oops, something went wrong :( Ask a mod about it~");
+        return;
+    }
 
     if pronouns.len() < 1 {
-        let _ = msg.reply(
+        let _ = api.reply(
+            msg,
             "You didn't list any pronouns. If you wanted the `No Pronouns` role, ask for `none`.",
         );
         return;
     }
 
-    let state = ::state(ctx);
-    let all_pronouns = state.pronouns.all_roles();
+    let all_pronouns = state.guild(guild).pronouns.all_roles();
 
     let mut cant_find = Vec::new();
     for pronoun in pronouns.iter() {
@@ -85,7 +87,7 @@ pub fn set_pronouns(ctx: &Context, msg: &Message, cmd: &mut Command) {
             Make sure you typed them correctly, and if they don't exist ask an admin to create them!",
             list
         );
-        let _ = msg.reply(&resp);
+        let _ = api.reply(msg, &resp);
         return;
     }
 
@@ -119,23 +121,100 @@ pub fn set_pronouns(ctx: &Context, msg: &Message, cmd: &mut Command) {
              different pronoun as your primary~",
             list, primary_pronoun,
         );
-        let _ = msg.reply(&resp);
+        let _ = api.reply(msg, &resp);
         return;
     }
 
-    let res = target.remove_roles(&remove_roles);
+    let res = api.remove_roles(guild, target, &remove_roles);
     if let Err(err) = res {
-        ::log(ctx, &format!("{:#?}", err));
-        let _ = msg.reply("Oops! Something went wrong! Ask an admin about it!");
+        api.log(&format!("{:#?}", err));
+        let _ = api.reply(msg, "Oops! Something went wrong! Ask an admin about it!");
         return;
     }
 
-    let res = target.add_roles(&add_roles);
+    let res = api.add_roles(guild, target, &add_roles);
     if let Err(err) = res {
-        ::log(ctx, &format!("{:#?}", err));
-        let _ = msg.reply("Oops! Something went wrong! Ask an admin about it!");
+        api.log(&format!("{:#?}", err));
+        let _ = api.reply(msg, "Oops! Something went wrong! Ask an admin about it!");
         return;
     }
 
-    let _ = msg.react("\u{1F44D}");
+    let _ = api.react(msg.channel, msg.id, "\u{1F44D}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use discord::fake::FakeGuild;
+
+    const CHANNEL: ChannelId = ChannelId(10);
+    const MEMBER: UserId = UserId(100);
+
+    fn setup() -> (FakeGuild, State) {
+        let guild = FakeGuild::new();
+        guild.add_member(MEMBER, &[RoleId(1), RoleId(32)]);
+
+        let state = State::in_memory();
+        *state.guild(guild.id).pronouns.roles.write().unwrap() = vec![
+            ("she/her".into(), RoleId(30)),
+            ("they/them".into(), RoleId(31)),
+            ("he/him".into(), RoleId(32)),
+            ("any".into(), RoleId(33)),
+        ];
+
+        (guild, state)
+    }
+
+    fn set(guild: &FakeGuild, state: &State, pronouns: &[&str]) -> MessageRef {
+        let msg = guild.post(CHANNEL, MEMBER, "", "2018-03-01T12:00:00Z");
+        let mut cmd = Command::pronouns(MEMBER, pronouns);
+        set_pronouns_with(guild, state, &msg, &mut cmd);
+        msg
+    }
+
+    #[test]
+    fn replaces_old_pronouns() {
+        let (guild, state) = setup();
+
+        let msg = set(&guild, &state, &["she/her", "they/them"]);
+
+        assert_eq!(guild.roles(MEMBER), vec![RoleId(1), RoleId(30), RoleId(31)]);
+        assert_eq!(guild.reactions(msg.id), vec!["\u{1F44D}"]);
+    }
+
+    #[test]
+    fn primary_pronouns_have_to_come_first_on_the_list() {
+        let (guild, state) = setup();
+
+        set(&guild, &state, &["they/them", "she/her"]);
+
+        assert_eq!(guild.roles(MEMBER), vec![RoleId(1), RoleId(32)]);
+        assert!(guild
+            .last_message(CHANNEL)
+            .unwrap()
+            .contains("can't currently give you she/her"));
+    }
+
+    #[test]
+    fn complains_about_unknown_pronouns() {
+        let (guild, state) = setup();
+
+        set(&guild, &state, &["she/her", "xe/xem"]);
+
+        assert_eq!(guild.roles(MEMBER), vec![RoleId(1), RoleId(32)]);
+        assert!(guild.last_message(CHANNEL).unwrap().contains("xe/xem"));
+    }
+
+    #[test]
+    fn needs_at_least_one_pronoun() {
+        let (guild, state) = setup();
+
+        set(&guild, &state, &[]);
+
+        assert_eq!(guild.roles(MEMBER), vec![RoleId(1), RoleId(32)]);
+        assert!(guild
+            .last_message(CHANNEL)
+            .unwrap()
+            .contains("didn't list any pronouns"));
+    }
 }
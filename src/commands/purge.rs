@@ -1,159 +1,612 @@
-use chrono::{DateTime, Duration, Utc};
-use serenity::Error;
+use chrono::{DateTime, Duration, FixedOffset, TimeZone, Utc};
+use regex::Regex;
 use serenity::model::prelude::*;
 use serenity::prelude::*;
 
+use std::cmp::min;
 use std::fmt;
-use std::str;
+use std::sync::Arc;
 use std::thread;
 use std::time::{self, SystemTime};
 
+use commands::ephemeral::{delete_chunk, describe_ttl};
+use discord::{logres, ApiError, ChannelMessage, DiscordApi, MessageRef, SerenityApi};
 use grammar::ast::Command;
-use {logres, staff_alert, state};
+use state::challenge::{Action, Event, Ping, Purge, Rejected, RuleChange, Stage};
+use state::State;
+use {archive, rng, staff_alert, state, StaffAlertData};
 
-pub fn issue_code(ctx: &Context, msg: &Message, _cmd: &Command) {
-    let state = state(ctx);
-    let staff_alert = staff_alert(ctx);
+pub fn issue_code(ctx: &Context, msg: &Message, cmd: &Command) {
+    let staff_alert = match guild_staff(ctx, msg) {
+        Some(staff_alert) => staff_alert,
+        None => return,
+    };
 
-    if msg.channel_id != staff_alert.admin_channel {
-        logres(ctx, msg.delete());
-        logres(
-            ctx,
-            staff_alert.admin_channel.say(format_args!(
-                "Psst, {}, do that in here ya goof",
-                msg.author.mention()
-            )),
-        );
+    issue_code_with(
+        &SerenityApi::new(ctx),
+        &state(ctx),
+        &staff_alert,
+        &msg.into(),
+        cmd,
+    );
+}
+
+pub fn issue_code_with(
+    api: &DiscordApi,
+    state: &State,
+    staff_alert: &StaffAlertData,
+    msg: &MessageRef,
+    cmd: &Command,
+) {
+    let action = match cmd {
+        Command::ChallengeCode(action) => *action,
+        _ => return,
+    };
+
+    if !in_admin_channel(api, staff_alert, msg) {
         return;
     }
 
-    let code = state.challenge_code.issue();
+    let guild = msg.guild.unwrap();
+    let challenge_code = &state.guild(guild).challenge_code;
+    let code = challenge_code.issue(
+        &mut rng::secret_rng(),
+        msg.author,
+        action,
+        Utc::now().timestamp(),
+    );
+    save_challenge_settings(api, state, guild);
     println!("Issue code: {}", code);
-    let result = msg.channel_id.say(format_args!(
-        "Okay {}, be extremely careful. Here's the admin destructive action code: {} ({})",
-        msg.author.mention(),
-        code,
-        "@everyone"
-    ));
-    logres(ctx, result);
+
+    let ping = match challenge_code.ping() {
+        Ping::Everyone => " (@everyone)".to_string(),
+        Ping::Role(role) => format!(" ({})", role.mention()),
+        Ping::Nobody => String::new(),
+    };
+    let result = api.say(
+        msg.channel,
+        &format!(
+            "Okay {}, be extremely careful. Here's the admin destructive action code for a {}: {} \
+             It only works for you, and only for the next {}.{}",
+            msg.author.mention(),
+            action,
+            code,
+            describe_ttl(challenge_code.expiry()),
+            ping
+        ),
+    );
+    logres(api, result);
 }
 
-pub fn purge_channel(ctx: &Context, msg: &Message, cmd: &Command) {
-    let (channel, time, time2, code) = match cmd {
-        Command::PurgeChannel(channel, time, time2, code) => {
-            (*channel, *time, *time2, code.clone())
+/// The code log is a record of who could do what, so a failure to save it
+/// is worth knowing about
+fn save_challenge_settings(api: &DiscordApi, state: &State, guild: GuildId) {
+    if let Err(e) = state.save_challenge_settings(guild) {
+        api.log(&format!("Couldn't save the challenge code log: {}", e));
+    }
+}
+
+pub fn set_code_setting(ctx: &Context, msg: &Message, cmd: &Command) {
+    set_code_setting_with(&SerenityApi::new(ctx), &state(ctx), &msg.into(), cmd);
+}
+
+pub fn set_code_setting_with(api: &DiscordApi, state: &State, msg: &MessageRef, cmd: &Command) {
+    let guild = msg.guild.unwrap();
+    let challenge_code = &state.guild(guild).challenge_code;
+    match cmd {
+        Command::SetChallengeExpiry(expiry) => challenge_code.set_expiry(*expiry),
+        Command::SetChallengePing(ping) => challenge_code.set_ping(*ping),
+        Command::SetTwoPersonRule(on) => {
+            let now = Utc::now().timestamp();
+            match challenge_code.set_two_person_rule(*on, msg.author, now) {
+                RuleChange::Requested => {
+                    api.log(&format!(
+                        "{} asked to turn off the two person rule",
+                        msg.author.mention()
+                    ));
+                    let _ = api.reply(
+                        msg,
+                        &format!(
+                            "Okay. Another admin has to say `turn off the two person rule` \
+                             too within {} before it's off.",
+                            describe_ttl(challenge_code.expiry())
+                        ),
+                    );
+                    return;
+                }
+                RuleChange::AlreadyRequested => {
+                    let _ = api.reply(
+                        msg,
+                        "You already asked! It needs a different admin to agree.",
+                    );
+                    return;
+                }
+                RuleChange::Changed => api.log(&format!(
+                    "{} turned {} the two person rule",
+                    msg.author.mention(),
+                    if *on { "on" } else { "off" }
+                )),
+                RuleChange::Unchanged => (),
+            }
         }
         _ => return,
+    }
+
+    if let Err(e) = state.save_challenge_settings(guild) {
+        api.log(&format!("Couldn't save the challenge code settings: {}", e));
+        let _ = api.reply(msg, "Oops, something went wrong :( Ask a mod about it~");
+        return;
+    }
+
+    let _ = api.react(msg.channel, msg.id, "\u{1F44D}");
+}
+
+pub fn list_codes(ctx: &Context, msg: &Message, cmd: &Command) {
+    let staff_alert = match guild_staff(ctx, msg) {
+        Some(staff_alert) => staff_alert,
+        None => return,
     };
 
-    let state = state(ctx);
-    let staff_alert = staff_alert(ctx);
+    list_codes_with(
+        &SerenityApi::new(ctx),
+        &state(ctx),
+        &staff_alert,
+        &msg.into(),
+        cmd,
+    );
+}
 
-    if msg.channel_id != staff_alert.admin_channel {
-        logres(ctx, msg.delete());
-        logres(
-            ctx,
-            staff_alert.admin_channel.say(format_args!(
-                "Psst, {}, do that in here ya goof",
-                msg.author.mention()
-            )),
-        );
+/// How many log entries get listed at once
+const MAX_LISTED: usize = 20;
+
+pub fn list_codes_with(
+    api: &DiscordApi,
+    state: &State,
+    staff_alert: &StaffAlertData,
+    msg: &MessageRef,
+    _cmd: &Command,
+) {
+    if !in_admin_channel(api, staff_alert, msg) {
         return;
     }
 
-    let real_code = state.challenge_code.take();
-    if Some(code.trim()) != real_code.as_ref().map(|s| s.trim()) {
-        println!("{:?} != {:?}", code, real_code);
-        let _ = msg.reply("Invalid challenge code. Start over.");
+    let guild = msg.guild.unwrap();
+    let challenge_code = &state.guild(guild).challenge_code;
+    if challenge_code.expire(Utc::now().timestamp()) {
+        save_challenge_settings(api, state, guild);
+    }
+    let log = challenge_code.log();
+    if log.is_empty() {
+        let _ = api.reply(msg, "No challenge codes have been issued yet.");
         return;
     }
 
-    let ctx = ctx.clone();
-    let msg = msg.clone();
-    thread::spawn(move || {
-        let mut messages = Vec::with_capacity(256);
+    let mut reply = String::from("Challenge codes, newest first:");
+    for entry in log.iter().rev().take(MAX_LISTED) {
+        let at = Utc.timestamp(entry.at, 0).to_rfc2822();
+        let admin = entry.admin.mention();
+        let line = match (entry.event, entry.by) {
+            (Event::TwoPersonRuleOn, _) => format!("{} turned on the two person rule", admin),
+            (Event::TwoPersonRuleOff, Some(by)) => format!(
+                "{} and {} turned off the two person rule",
+                admin,
+                by.mention()
+            ),
+            (Event::Used, Some(by)) | (Event::Cancelled, Some(by)) if by != entry.admin => format!(
+                "{}'s {} code was {} by {}",
+                admin,
+                entry.action,
+                entry.event,
+                by.mention()
+            ),
+            (event, _) => format!("{}'s {} code was {}", admin, entry.action, event),
+        };
+        reply.push_str(&format!("\n{}: {}", at, line));
+    }
+    logres(api, api.reply(msg, &reply));
+}
 
-        let get_msgs = |messages: &mut Vec<MessageId>| -> Result<(), Error> {
-            let mut filtered = false;
-            let mut msgs = channel.messages(|get| get.most_recent().limit(100))?;
-            msgs.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+fn guild_staff(ctx: &Context, msg: &Message) -> Option<Arc<StaffAlertData>> {
+    msg.guild_id().and_then(|guild| staff_alert(ctx, guild))
+}
 
-            let mut i = 0;
-            loop {
-                messages.extend(
-                    msgs.iter()
-                        .rev()
-                        .filter(|msg| {
-                            if msg.timestamp.timestamp() < time.timestamp() {
-                                filtered = true;
-                                false
-                            } else {
-                                true
-                            }
-                        })
-                        .filter(|msg| msg.timestamp.timestamp() < time2.timestamp())
-                        .filter(|msg| !msg.pinned)
-                        .map(|m| m.id),
-                );
-
-                if filtered || msgs.len() < 100 {
-                    break;
-                }
+/// Destructive commands only work in the admin channel. Anywhere else they
+/// get deleted before too many people see them.
+fn in_admin_channel(api: &DiscordApi, staff_alert: &StaffAlertData, msg: &MessageRef) -> bool {
+    if msg.channel == staff_alert.admin_channel {
+        return true;
+    }
 
-                i += 1;
-                if i == 1 {
-                    let _ = msg.reply("I'm gathering a lot of messages. This could take a while depending on the range you asked for.");
-                }
-                if i % 25 == 0 {
-                    let status = format!("Large delete gather progress: {}", messages.len());
-                    let _ = msg.reply(&status);
-                    println!("{}", status);
-                }
+    logres(api, api.delete_message(msg.channel, msg.id));
+    logres(
+        api,
+        api.say(
+            staff_alert.admin_channel,
+            &format!("Psst, {}, do that in here ya goof", msg.author.mention()),
+        ),
+    );
+    false
+}
+
+/// Only one purge runs at a time, though one that's still waiting for
+/// confirmation can be replaced
+fn purge_running(state: &State, guild: GuildId) -> bool {
+    state
+        .guild(guild)
+        .challenge_code
+        .purge()
+        .map_or(false, |purge| purge.stage != Stage::Confirming)
+}
+
+fn save_purge(api: &DiscordApi, state: &State, guild: GuildId) {
+    if let Err(e) = state.save_purge(guild) {
+        api.log(&format!("Couldn't save the purge's progress: {}", e));
+    }
+}
 
-                let first = msgs[0].id;
-                thread::sleep(time::Duration::from_millis(100));
-                msgs = channel.messages(|get| get.before(first).limit(100))?;
-                msgs.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+/// Forgets about the purge, when it's done or can't go on
+fn end_purge(api: &DiscordApi, state: &State, guild: GuildId) {
+    state.guild(guild).challenge_code.set_purge(None);
+    save_purge(api, state, guild);
+}
+
+/// Like `end_purge`, for a thread working on purge request `number`. If
+/// that one was cancelled and another started since, it's left alone.
+fn end_request(api: &DiscordApi, state: &State, guild: GuildId, number: u32) {
+    if state
+        .guild(guild)
+        .challenge_code
+        .take_purge_if(number)
+        .is_some()
+    {
+        save_purge(api, state, guild);
+    }
+}
+
+/// Which messages a purge goes through
+#[derive(Clone, Debug)]
+pub enum Range {
+    /// Everything strictly between two times
+    Between(DateTime<FixedOffset>, DateTime<FixedOffset>),
+    /// From one message to another, both included
+    Messages(MessageLink, MessageLink),
+}
+
+/// A message that a range starts or ends at. A link also says which guild
+/// and channel the message is in, but a bare id doesn't.
+#[derive(Clone, Copy, Debug)]
+pub struct MessageLink {
+    pub place: Option<(GuildId, ChannelId)>,
+    pub id: MessageId,
+}
+
+impl Range {
+    /// The last `seconds` up to now
+    pub fn last(seconds: u64) -> Range {
+        let now = Utc::now().with_timezone(&FixedOffset::east(0));
+        Range::Between(now - Duration::seconds(seconds as i64), now)
+    }
+
+    /// Whether `msg` is older than anything in the range, so that there's no
+    /// need to look any further back
+    fn is_past(&self, msg: &ChannelMessage) -> bool {
+        match *self {
+            Range::Between(after, _) => msg.timestamp.timestamp() < after.timestamp(),
+            Range::Messages(first, _) => msg.id < first.id,
+        }
+    }
+
+    fn contains(&self, msg: &ChannelMessage) -> bool {
+        match *self {
+            Range::Between(after, before) => {
+                after.timestamp() <= msg.timestamp.timestamp()
+                    && msg.timestamp.timestamp() < before.timestamp()
             }
+            Range::Messages(first, last) => first.id <= msg.id && msg.id <= last.id,
+        }
+    }
+
+    /// Whether the range could be in `channel`, which it can't if it's
+    /// between links to somewhere else
+    fn is_in(&self, guild: GuildId, channel: ChannelId) -> bool {
+        match *self {
+            Range::Between(..) => true,
+            Range::Messages(first, last) => [first, last]
+                .iter()
+                .all(|link| link.place.map_or(true, |place| place == (guild, channel))),
+        }
+    }
+}
+
+/// Narrows down what a purge deletes. Pinned messages are always left alone
+/// unless there's an `IncludingPinned`.
+#[derive(Clone, Debug)]
+pub enum Filter {
+    /// Only messages from any of these
+    From(Vec<UserId>),
+    /// Only messages that match, ignoring case. A plain word works too.
+    Matching(String),
+    IncludingPinned,
+}
+
+/// All of a purge's filters, ready to check messages against
+struct Filters {
+    authors: Vec<UserId>,
+    patterns: Vec<Regex>,
+    include_pinned: bool,
+}
 
-            Ok(())
+impl Filters {
+    fn new(filters: &[Filter]) -> Result<Filters, String> {
+        let mut all = Filters {
+            authors: Vec::new(),
+            patterns: Vec::new(),
+            include_pinned: false,
         };
+        for filter in filters {
+            match filter {
+                Filter::From(users) => all.authors.extend(users),
+                Filter::Matching(pattern) => {
+                    let regex = Regex::new(&format!("(?i){}", pattern))
+                        .map_err(|e| format!("`{}` doesn't work as a pattern: {}", pattern, e))?;
+                    all.patterns.push(regex);
+                }
+                Filter::IncludingPinned => all.include_pinned = true,
+            }
+        }
+        Ok(all)
+    }
+
+    fn matches(&self, msg: &ChannelMessage) -> bool {
+        (self.include_pinned || !msg.pinned)
+            && (self.authors.is_empty() || self.authors.contains(&msg.author))
+            && self
+                .patterns
+                .iter()
+                .all(|regex| regex.is_match(&msg.content))
+    }
+}
 
-        match get_msgs(&mut messages) {
-            Ok(()) => (),
-            r => {
-                logres(&ctx, r);
-                return;
+/// The filters as they'd go on the end of a sentence
+fn describe_filters(filters: &[Filter]) -> String {
+    let mut out = String::new();
+    for filter in filters {
+        match filter {
+            Filter::From(users) => {
+                let users = users.iter().map(|user| user.mention()).collect::<Vec<_>>();
+                out.push_str(&format!(", only from {}", users.join(" and ")));
             }
-        };
+            Filter::Matching(pattern) => out.push_str(&format!(", matching `{}`", pattern)),
+            Filter::IncludingPinned => out.push_str(", including pinned ones"),
+        }
+    }
+    out
+}
+
+pub fn purge_channel(ctx: &Context, msg: &Message, cmd: &Command) {
+    let (channel, range, filters, code) = match cmd {
+        Command::PurgeChannel(channel, range, filters, code) => {
+            (*channel, range.clone(), filters.clone(), code.clone())
+        }
+        _ => return,
+    };
 
-        let now: DateTime<Utc> = SystemTime::now().into();
-        let time_diff = now.timestamp() - time.timestamp();
-        let time_diff = Duration::seconds(time_diff);
-        let time_diff2 = now.timestamp() - time2.timestamp();
-        let time_diff2 = Duration::seconds(time_diff2);
+    let staff_alert = match guild_staff(ctx, msg) {
+        Some(staff_alert) => staff_alert,
+        None => return,
+    };
 
-        let count = messages.len();
-        let reply = format!(
-            "Okay, you're about to delete {} messages from {} after {} ({} ago) and before {} ({} ago). Please confirm.",
-            count,
-            channel.mention(),
-            time.to_rfc2822(),
-            DurationFmt(time_diff),
-            time2.to_rfc2822(),
-            DurationFmt(time_diff2),
+    // Gathering can take a good while, so it happens in the background
+    let ctx = ctx.clone();
+    let msg = MessageRef::from(msg);
+    thread::spawn(move || {
+        let cmd = Command::PurgeChannel(channel, range, filters, code);
+        purge_channel_with(
+            &SerenityApi::new(&ctx),
+            &state(&ctx),
+            &staff_alert,
+            &msg,
+            &cmd,
         );
+    });
+}
 
-        let _ = msg.reply(&reply);
+pub fn purge_channel_with(
+    api: &DiscordApi,
+    state: &State,
+    staff_alert: &StaffAlertData,
+    msg: &MessageRef,
+    cmd: &Command,
+) {
+    let (channel, range, filters, code) = match cmd {
+        Command::PurgeChannel(channel, range, filters, code) => (*channel, range, filters, code),
+        _ => return,
+    };
 
-        state.challenge_code.set_purge(Some((channel, messages)));
+    if !in_admin_channel(api, staff_alert, msg) {
+        return;
+    }
+
+    let guild = msg.guild.unwrap();
+    if purge_running(state, guild) {
+        let _ = api.reply(
+            msg,
+            "There's already a purge going. Wait for it to finish, or cancel it.",
+        );
+        return;
+    }
+
+    let redeemed = state.guild(guild).challenge_code.redeem(
+        code,
+        msg.author,
+        Action::Purge,
+        Utc::now().timestamp(),
+    );
+    save_challenge_settings(api, state, guild);
+    if let Err(rejected) = redeemed {
+        let reason = match rejected {
+            Rejected::NoCode | Rejected::Wrong => "Invalid challenge code.".to_string(),
+            Rejected::Expired => "That challenge code has expired.".to_string(),
+            Rejected::NotYours => "That challenge code was issued to someone else.".to_string(),
+            Rejected::OtherAction(action) => format!("That challenge code was for a {}.", action),
+        };
+        let _ = api.reply(msg, &format!("{} Start over.", reason));
+        return;
+    }
+
+    let matcher = match Filters::new(filters) {
+        Ok(matcher) => matcher,
+        Err(e) => {
+            let _ = api.reply(msg, &format!("{}. Start over.", e));
+            return;
+        }
+    };
+    if !range.is_in(guild, channel) {
+        let _ = api.reply(
+            msg,
+            &format!(
+                "Those message links aren't in {}. Start over.",
+                channel.mention()
+            ),
+        );
+        return;
+    }
+
+    let number = state.guild(guild).challenge_code.next_request();
+    save_challenge_settings(api, state, guild);
+    state.guild(guild).challenge_code.set_purge(Some(Purge {
+        number,
+        channel,
+        requested_by: msg.author,
+        stage: Stage::Gathering,
+        messages: Vec::new(),
+        deleted: 0,
+    }));
+    save_purge(api, state, guild);
+
+    let mut messages = Vec::with_capacity(256);
+
+    let get_msgs = |messages: &mut Vec<ChannelMessage>| -> Result<(), ApiError> {
+        let mut filtered = false;
+        let mut msgs = api.messages(channel, None, 100)?;
+        msgs.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+
+        let mut i = 0;
+        loop {
+            messages.extend(
+                msgs.iter()
+                    .rev()
+                    .filter(|msg| {
+                        if range.is_past(msg) {
+                            filtered = true;
+                            false
+                        } else {
+                            true
+                        }
+                    })
+                    .filter(|msg| range.contains(msg))
+                    .filter(|msg| matcher.matches(msg))
+                    .cloned(),
+            );
+
+            if filtered || msgs.len() < 100 {
+                break;
+            }
+
+            i += 1;
+            if i == 1 {
+                let _ = api.reply(msg, "I'm gathering a lot of messages. This could take a while depending on the range you asked for.");
+            }
+            if i % 25 == 0 {
+                let status = format!("Large delete gather progress: {}", messages.len());
+                let _ = api.reply(msg, &status);
+                println!("{}", status);
+            }
+
+            let first = msgs[0].id;
+            thread::sleep(time::Duration::from_millis(100));
+            msgs = api.messages(channel, Some(first), 100)?;
+            msgs.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+        }
+
+        Ok(())
+    };
+
+    match get_msgs(&mut messages) {
+        Ok(()) => (),
+        r => {
+            logres(api, r);
+            end_request(api, state, guild, number);
+            return;
+        }
+    };
+
+    let now: DateTime<Utc> = SystemTime::now().into();
+    let range_desc = match *range {
+        Range::Between(time, time2) => {
+            let time_diff = Duration::seconds(now.timestamp() - time.timestamp());
+            let time_diff2 = Duration::seconds(now.timestamp() - time2.timestamp());
+            format!(
+                "after {} ({} ago) and before {} ({} ago)",
+                time.to_rfc2822(),
+                DurationFmt(time_diff),
+                time2.to_rfc2822(),
+                DurationFmt(time_diff2),
+            )
+        }
+        Range::Messages(first, last) => {
+            format!("from message {} to message {}", first.id, last.id)
+        }
+    };
+
+    // Nothing gets deleted without a record of it
+    let transcript = match archive::write(channel, &messages, now) {
+        Ok(transcript) => transcript,
+        Err(e) => {
+            api.log(&format!(
+                "Couldn't write a transcript of {}: {}",
+                channel.mention(),
+                e
+            ));
+            let _ = api.reply(
+                msg,
+                "I couldn't save a transcript of those messages, so I won't delete them. Start over!",
+            );
+            end_request(api, state, guild, number);
+            return;
+        }
+    };
+
+    let count = messages.len();
+    let reply = format!(
+        "Okay, purge request #{} is about to delete {} messages from {} {}{}. \
+         They've been saved to `{}` and `{}`. Please confirm.",
+        number,
+        count,
+        channel.mention(),
+        range_desc,
+        describe_filters(filters),
+        transcript.json.display(),
+        transcript.markdown.display(),
+    );
+
+    let messages = messages.into_iter().map(|msg| msg.id).collect();
+    let gathered = state.guild(guild).challenge_code.update_purge(|purge| {
+        if purge.number == number && purge.stage == Stage::Gathering {
+            purge.stage = Stage::Confirming;
+            purge.messages = messages;
+            true
+        } else {
+            false
+        }
     });
-}
+    if gathered != Some(true) {
+        // It was cancelled in the meantime, and maybe another one started
+        return;
+    }
+    save_purge(api, state, guild);
 
-fn header_int(data: Option<&[Vec<u8>]>) -> Option<u64> {
-    data.and_then(|parts| parts.get(0))
-        .and_then(|part| str::from_utf8(part).ok())
-        .and_then(|part| part.parse().ok())
+    let _ = api.reply(msg, &reply);
 }
 
 pub fn execute_purge(ctx: &Context, msg: &Message, cmd: &Command) {
@@ -162,92 +615,330 @@ pub fn execute_purge(ctx: &Context, msg: &Message, cmd: &Command) {
         _ => return,
     };
 
-    let state = state(ctx);
-    let staff_alert = staff_alert(ctx);
+    let staff_alert = match guild_staff(ctx, msg) {
+        Some(staff_alert) => staff_alert,
+        None => return,
+    };
 
-    if msg.channel_id != staff_alert.admin_channel {
-        logres(ctx, msg.delete());
-        logres(
-            ctx,
-            staff_alert.admin_channel.say(format_args!(
-                "Psst, {}, do that in here ya goof",
-                msg.author.mention()
-            )),
+    let ctx = ctx.clone();
+    let msg = MessageRef::from(msg);
+    thread::spawn(move || {
+        execute_purge_with(
+            &SerenityApi::new(&ctx),
+            &state(&ctx),
+            &staff_alert,
+            &msg,
+            &Command::ExecutePurge(num),
         );
+    });
+}
+
+pub fn execute_purge_with(
+    api: &DiscordApi,
+    state: &State,
+    staff_alert: &StaffAlertData,
+    msg: &MessageRef,
+    cmd: &Command,
+) {
+    let num = match cmd {
+        Command::ExecutePurge(num) => *num,
+        _ => return,
+    };
+
+    if !in_admin_channel(api, staff_alert, msg) {
         return;
     }
 
-    let (channel, messages) = match state.challenge_code.take_purge() {
-        Some((channel, messages)) => (channel, messages),
-        _ => {
-            logres(ctx, msg.reply("You didn't set it up. Start over!"));
+    let guild = msg.guild.unwrap();
+    let challenge_code = &state.guild(guild).challenge_code;
+    let next = if challenge_code.two_person_rule() {
+        Stage::Seconding
+    } else {
+        Stage::Deleting
+    };
+    // Checked and moved on in one go, so that only one confirmation gets to
+    // start deleting however many come in at once
+    let confirmed = challenge_code.update_purge(|purge| match purge.stage {
+        Stage::Confirming if num as usize == purge.messages.len() => {
+            purge.stage = next;
+            Ok(purge.clone())
+        }
+        stage => Err(stage),
+    });
+    let purge = match confirmed {
+        Some(Ok(purge)) => purge,
+        // Still waiting, so it was the count that was wrong
+        Some(Err(Stage::Confirming)) => {
+            end_purge(api, state, guild);
+            let _ = api.reply(msg, "Wrong number of messages. Start over!");
+            return;
+        }
+        Some(Err(_)) => {
+            let _ = api.reply(msg, "That purge is already going!");
+            return;
+        }
+        None => {
+            logres(api, api.reply(msg, "You didn't set it up. Start over!"));
             return;
         }
     };
+    save_purge(api, state, guild);
 
-    if num as usize != messages.len() {
-        let _ = msg.reply("Wrong number of messages. Start over!");
+    if purge.stage == Stage::Seconding {
+        let _ = api.reply(
+            msg,
+            &format!(
+                "Purge request #{0} needs a second admin. Another admin can say \
+                 `I confirm purge request #{0}` to go ahead, or an admin can cancel it.",
+                purge.number
+            ),
+        );
         return;
     }
 
+    delete_purge(api, state, staff_alert, guild, purge);
+}
+
+pub fn confirm_purge(ctx: &Context, msg: &Message, cmd: &Command) {
+    let number = match cmd {
+        Command::ConfirmAction(Action::Purge, number) => *number,
+        _ => return,
+    };
+
+    let staff_alert = match guild_staff(ctx, msg) {
+        Some(staff_alert) => staff_alert,
+        None => return,
+    };
+
     let ctx = ctx.clone();
-    let msg = msg.clone();
+    let msg = MessageRef::from(msg);
     thread::spawn(move || {
-        let mut i = 0;
-        let mut j_i = -1isize as usize;
-        for (j, chunk) in messages.chunks(100).enumerate() {
-            loop {
-                let res = channel.delete_messages(chunk);
-                match res {
-                    Ok(_) => (),
-                    Err(Error::Http(HttpError::UnsuccessfulRequest(ref resp)))
-                        if header_int(resp.headers.get_raw("X-RateLimit-Remaining")) == Some(0)
-                            && j_i != j =>
-                    {
-                        let now = time::SystemTime::now();
-                        let reset = header_int(resp.headers.get_raw("X-RateLimit-Reset"))
-                            .map(|reset| time::UNIX_EPOCH + time::Duration::from_secs(reset))
-                            .unwrap_or(now.clone());
-                        let wait = reset
-                            .duration_since(now)
-                            .unwrap_or(time::Duration::from_secs(0))
-                            + time::Duration::from_secs(5);
-                        let _ = msg.reply(&format!(
-                            "Oops, hit a rate limit! Resets at {}. Waiting for {}",
-                            DateTime::<Utc>::from(reset).to_rfc2822(),
-                            DurationFmt(Duration::from_std(wait).unwrap_or(Duration::seconds(0))),
-                        ));
-                        i = 9;
-                        j_i = j;
-                        thread::sleep(wait);
-                        continue;
-                    }
-                    err => {
-                        thread::sleep(time::Duration::from_millis(200));
-                        logres(&ctx, err);
-                        thread::sleep(time::Duration::from_millis(200));
-                        let _ = msg.reply("Something went wrong");
-                        return;
-                    }
-                }
-                thread::sleep(time::Duration::from_millis(100));
-                break;
+        confirm_purge_with(
+            &SerenityApi::new(&ctx),
+            &state(&ctx),
+            &staff_alert,
+            &msg,
+            &Command::ConfirmAction(Action::Purge, number),
+        );
+    });
+}
+
+/// A second admin going along with a purge, under the two person rule
+pub fn confirm_purge_with(
+    api: &DiscordApi,
+    state: &State,
+    staff_alert: &StaffAlertData,
+    msg: &MessageRef,
+    cmd: &Command,
+) {
+    let number = match cmd {
+        Command::ConfirmAction(Action::Purge, number) => *number,
+        _ => return,
+    };
+
+    if !in_admin_channel(api, staff_alert, msg) {
+        return;
+    }
+
+    let guild = msg.guild.unwrap();
+    // Like confirming the count, only one second admin gets to start it
+    let seconded = state.guild(guild).challenge_code.update_purge(|purge| {
+        let waiting = purge.number == number && purge.stage == Stage::Seconding;
+        if waiting && purge.requested_by != msg.author {
+            purge.stage = Stage::Deleting;
+            Ok(purge.clone())
+        } else {
+            Err(waiting)
+        }
+    });
+    let purge = match seconded {
+        Some(Ok(purge)) => purge,
+        // Waiting, but for someone other than whoever asked for it
+        Some(Err(true)) => {
+            let _ = api.reply(
+                msg,
+                "It has to be a different admin from whoever asked for the purge!",
+            );
+            return;
+        }
+        _ => {
+            let _ = api.reply(
+                msg,
+                &format!("Purge request #{} isn't waiting for anyone.", number),
+            );
+            return;
+        }
+    };
+    save_purge(api, state, guild);
+
+    delete_purge(api, state, staff_alert, guild, purge);
+}
+
+/// Picks up a purge that a restart cut short. Deleting carries on from where
+/// it got to, but gathering has to start over, and a purge that's waiting
+/// for confirmation just keeps waiting.
+pub fn resume_purge(api: &DiscordApi, state: &State, staff_alert: &StaffAlertData, guild: GuildId) {
+    let purge = match state.guild(guild).challenge_code.purge() {
+        Some(purge) => purge,
+        None => return,
+    };
+
+    match purge.stage {
+        Stage::Gathering => {
+            end_purge(api, state, guild);
+            tell(
+                api,
+                staff_alert,
+                &purge,
+                &format!(
+                    "I restarted while gathering messages from {}, so nothing was deleted. Start over!",
+                    purge.channel.mention()
+                ),
+            );
+        }
+        Stage::Confirming => tell(
+            api,
+            staff_alert,
+            &purge,
+            &format!(
+                "I restarted, but the purge of {} messages from {} is still waiting for you to confirm it.",
+                purge.messages.len(),
+                purge.channel.mention()
+            ),
+        ),
+        Stage::Seconding => tell(
+            api,
+            staff_alert,
+            &purge,
+            &format!(
+                "I restarted, but purge request #{} is still waiting for a second admin to confirm it.",
+                purge.number
+            ),
+        ),
+        Stage::Deleting => {
+            tell(
+                api,
+                staff_alert,
+                &purge,
+                &format!(
+                    "I restarted in the middle of purging {}, after deleting {} of {} messages. Carrying on with the rest...",
+                    purge.channel.mention(),
+                    purge.deleted,
+                    purge.messages.len()
+                ),
+            );
+            delete_purge(api, state, staff_alert, guild, purge);
+        }
+    }
+}
+
+/// Lets whoever asked for the purge know how it's going
+fn tell(api: &DiscordApi, staff_alert: &StaffAlertData, purge: &Purge, content: &str) {
+    let result = api.say(
+        staff_alert.admin_channel,
+        &format!("{}: {}", purge.requested_by.mention(), content),
+    );
+    logres(api, result);
+}
+
+/// Deletes what's left of a confirmed purge, saving how far it's got after
+/// every chunk. Cancelling the purge stops it between chunks.
+fn delete_purge(
+    api: &DiscordApi,
+    state: &State,
+    staff_alert: &StaffAlertData,
+    guild: GuildId,
+    mut purge: Purge,
+) {
+    let number = purge.number;
+    let total = purge.messages.len();
+    let mut i = 0;
+    let mut failed = Vec::new();
+    while purge.deleted < total {
+        let chunk = purge.remaining()[..min(100, total - purge.deleted)].to_vec();
+        let rate_limited = match delete_chunk(api, purge.channel, &chunk) {
+            Ok(errors) => {
+                failed.extend(errors);
+                purge.deleted += chunk.len();
+                false
             }
+            Err(deleted) => {
+                purge.deleted += deleted;
+                true
+            }
+        };
 
-            i += 1;
-            if i % 10 == 0 {
-                let _ = msg.reply(&format!("{} messages deleted...", j * 100));
+        let deleted = purge.deleted;
+        let updated = state.guild(guild).challenge_code.update_purge(|purge| {
+            if purge.number == number {
+                purge.deleted = deleted;
+                true
+            } else {
+                false
             }
+        });
+        if updated != Some(true) {
+            tell(
+                api,
+                staff_alert,
+                &purge,
+                &format!("Stopped after deleting {} of {} messages.", deleted, total),
+            );
+            return;
         }
+        save_purge(api, state, guild);
 
-        logres(&ctx, msg.reply("DONE!~ *Phew*"));
-    });
+        if rate_limited {
+            end_request(api, state, guild, number);
+            tell(
+                api,
+                staff_alert,
+                &purge,
+                &format!(
+                    "Discord kept rate limiting me, so I stopped after deleting {} of {} messages",
+                    deleted, total
+                ),
+            );
+            return;
+        }
+
+        i += 1;
+        if i % 10 == 0 {
+            tell(
+                api,
+                staff_alert,
+                &purge,
+                &format!("{} messages deleted...", deleted),
+            );
+        }
+    }
+
+    end_request(api, state, guild, number);
+    if let Some(e) = failed.first() {
+        api.log(&format!(
+            "Couldn't delete {} messages in {} during purge request #{}: {:?}",
+            failed.len(),
+            purge.channel.mention(),
+            purge.number,
+            e
+        ));
+    }
+    tell(api, staff_alert, &purge, "DONE!~ *Phew*");
 }
 
-pub fn cancel_purge(ctx: &Context) {
+pub fn cancel_purge(ctx: &Context, msg: &Message) {
+    let guild = match msg.guild_id() {
+        Some(guild) => guild,
+        None => return,
+    };
+    let api = SerenityApi::new(ctx);
     let state = state(ctx);
-    state.challenge_code.take();
-    state.challenge_code.take_purge();
+    state
+        .guild(guild)
+        .challenge_code
+        .cancel(msg.author.id, Utc::now().timestamp());
+    save_challenge_settings(&api, &state, guild);
+    end_purge(&api, &state, guild);
 }
 
 struct DurationFmt(Duration);
@@ -273,3 +964,778 @@ impl fmt::Display for DurationFmt {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use discord::fake::{self, FakeGuild};
+    use state::challenge::DEFAULT_EXPIRY;
+
+    use std::fs;
+
+    const TARGET: ChannelId = ChannelId(50);
+    const ADMIN: UserId = UserId(100);
+
+    /// Issues a purge code to `ADMIN` as if it was `now`
+    fn issue(state: &State, guild: &FakeGuild, now: i64) -> String {
+        state
+            .guild(guild.id)
+            .challenge_code
+            .issue(&mut rng::seeded(1), ADMIN, Action::Purge, now)
+    }
+
+    fn request(guild: &FakeGuild, state: &State, after: &str, before: &str) -> MessageRef {
+        let range = Range::Between(fake::time(after), fake::time(before));
+        request_with(guild, state, range, Vec::new())
+    }
+
+    fn request_with(
+        guild: &FakeGuild,
+        state: &State,
+        range: Range,
+        filters: Vec<Filter>,
+    ) -> MessageRef {
+        let staff_alert = fake::staff_alert();
+        let code = issue(state, guild, Utc::now().timestamp());
+        let msg = guild.post(staff_alert.admin_channel, ADMIN, "", "2018-03-01T12:00:00Z");
+        let cmd = Command::PurgeChannel(TARGET, range, filters, code);
+        purge_channel_with(guild, state, &staff_alert, &msg, &cmd);
+        msg
+    }
+
+    /// Requests a purge and goes through with it
+    fn purge(guild: &FakeGuild, state: &State, range: Range, filters: Vec<Filter>, count: u32) {
+        let msg = request_with(guild, state, range, filters);
+        let cmd = Command::ExecutePurge(count);
+        execute_purge_with(guild, state, &fake::staff_alert(), &msg, &cmd);
+    }
+
+    /// A link to a message in `TARGET`
+    fn link(guild: &FakeGuild, id: MessageId) -> MessageLink {
+        MessageLink {
+            place: Some((guild.id, TARGET)),
+            id,
+        }
+    }
+
+    /// Posts a message a second starting at 11:00
+    fn spam(guild: &FakeGuild, count: u32) {
+        for i in 0..count {
+            let time = format!("2018-03-01T11:{:02}:{:02}Z", i / 60, i % 60);
+            guild.post(TARGET, UserId(1), "spam", &time);
+        }
+    }
+
+    #[test]
+    fn gathers_unpinned_messages_in_range() {
+        let guild = FakeGuild::new();
+        let state = State::in_memory();
+        guild.post(TARGET, UserId(1), "too early", "2018-03-01T10:00:00Z");
+        guild.post(TARGET, UserId(1), "first", "2018-03-01T11:00:00Z");
+        let pinned = guild.post(TARGET, UserId(1), "pinned", "2018-03-01T12:00:00Z");
+        guild.pin(TARGET, pinned.id);
+        guild.post(TARGET, UserId(1), "second", "2018-03-01T13:00:00Z");
+        guild.post(TARGET, UserId(1), "too late", "2018-03-01T14:00:00Z");
+
+        request(
+            &guild,
+            &state,
+            "2018-03-01T10:30:00Z",
+            "2018-03-01T13:30:00Z",
+        );
+
+        let purge = state.guild(guild.id).challenge_code.take_purge().unwrap();
+        assert_eq!(purge.channel, TARGET);
+        assert_eq!(purge.stage, Stage::Confirming);
+        assert_eq!(purge.messages.len(), 2);
+        assert!(guild
+            .last_message(fake::staff_alert().admin_channel)
+            .unwrap()
+            .contains("delete 2 messages"));
+    }
+
+    #[test]
+    fn saves_a_transcript_first() {
+        let guild = FakeGuild::new();
+        let state = State::in_memory();
+        let first = guild.post(TARGET, UserId(1), "first", "2018-03-01T11:00:00Z");
+        guild.edit(TARGET, first.id, "first, edited", "2018-03-01T11:05:00Z");
+        guild.attach(TARGET, first.id, "https://example.com/cat.png");
+        guild.post(TARGET, UserId(2), "second\nline", "2018-03-01T11:10:00Z");
+
+        request(
+            &guild,
+            &state,
+            "2018-03-01T10:00:00Z",
+            "2018-03-01T12:00:00Z",
+        );
+
+        let reply = guild
+            .last_message(fake::staff_alert().admin_channel)
+            .unwrap();
+        let paths = reply.split('`').collect::<Vec<_>>();
+        assert!(paths[1].ends_with(".json"));
+        assert!(paths[3].ends_with(".md"));
+
+        let transcript = fs::read_to_string(paths[1]).unwrap();
+        assert!(transcript.contains("\"attachments\": [\n        \"https://example.com/cat.png\""));
+
+        let markdown = fs::read_to_string(paths[3]).unwrap();
+        assert!(markdown.contains("**user1#0001** (1)"));
+        assert!(markdown.contains("(edited "));
+        assert!(markdown.contains("> first, edited\n"));
+        assert!(markdown.contains("Attachment: https://example.com/cat.png"));
+        assert!(markdown.contains("> second\n> line\n"));
+        assert!(markdown.find("first").unwrap() < markdown.find("second").unwrap());
+    }
+
+    #[test]
+    fn only_gathers_from_the_given_authors() {
+        let guild = FakeGuild::new();
+        let state = State::in_memory();
+        guild.post(TARGET, UserId(1), "raider one", "2018-03-01T11:00:00Z");
+        guild.post(TARGET, UserId(2), "regular", "2018-03-01T11:01:00Z");
+        guild.post(TARGET, UserId(3), "raider two", "2018-03-01T11:02:00Z");
+
+        let range = Range::Between(
+            fake::time("2018-03-01T10:00:00Z"),
+            fake::time("2018-03-01T12:00:00Z"),
+        );
+        let filters = vec![Filter::From(vec![UserId(1), UserId(3)])];
+        purge(&guild, &state, range, filters, 2);
+
+        assert_eq!(guild.contents(TARGET), vec!["regular"]);
+    }
+
+    #[test]
+    fn gathers_matching_messages_between_links_including_pinned() {
+        let guild = FakeGuild::new();
+        let state = State::in_memory();
+        guild.post(
+            TARGET,
+            UserId(1),
+            "FREE NITRO before",
+            "2018-03-01T11:00:00Z",
+        );
+        let first = guild.post(TARGET, UserId(1), "Free Nitro here", "2018-03-01T11:01:00Z");
+        guild.post(TARGET, UserId(1), "hello", "2018-03-01T11:02:00Z");
+        let pinned = guild.post(
+            TARGET,
+            UserId(1),
+            "free nitro pinned",
+            "2018-03-01T11:03:00Z",
+        );
+        guild.pin(TARGET, pinned.id);
+        guild.post(
+            TARGET,
+            UserId(1),
+            "free nitro after",
+            "2018-03-01T11:04:00Z",
+        );
+
+        let filters = vec![
+            Filter::Matching("free nitro".into()),
+            Filter::IncludingPinned,
+        ];
+        purge(
+            &guild,
+            &state,
+            Range::Messages(link(&guild, first.id), link(&guild, pinned.id)),
+            filters,
+            2,
+        );
+
+        assert_eq!(
+            guild.contents(TARGET),
+            vec!["FREE NITRO before", "hello", "free nitro after"]
+        );
+    }
+
+    #[test]
+    fn rejects_broken_patterns() {
+        let guild = FakeGuild::new();
+        let state = State::in_memory();
+        let range = Range::Between(
+            fake::time("2018-03-01T10:00:00Z"),
+            fake::time("2018-03-01T12:00:00Z"),
+        );
+        request_with(
+            &guild,
+            &state,
+            range,
+            vec![Filter::Matching("(oops".into())],
+        );
+
+        assert!(state.guild(guild.id).challenge_code.take_purge().is_none());
+        assert!(guild
+            .last_message(fake::staff_alert().admin_channel)
+            .unwrap()
+            .contains("doesn't work as a pattern"));
+    }
+
+    #[test]
+    fn rejects_links_to_other_channels() {
+        let guild = FakeGuild::new();
+        let state = State::in_memory();
+        let first = guild.post(TARGET, UserId(1), "first", "2018-03-01T11:00:00Z");
+        let elsewhere = MessageLink {
+            place: Some((guild.id, ChannelId(51))),
+            id: MessageId(first.id.0 + 1),
+        };
+        request_with(
+            &guild,
+            &state,
+            Range::Messages(link(&guild, first.id), elsewhere),
+            Vec::new(),
+        );
+
+        assert!(state.guild(guild.id).challenge_code.take_purge().is_none());
+        assert!(guild
+            .last_message(fake::staff_alert().admin_channel)
+            .unwrap()
+            .contains("aren't in"));
+    }
+
+    #[test]
+    fn gathers_more_than_one_page() {
+        let guild = FakeGuild::new();
+        let state = State::in_memory();
+        spam(&guild, 250);
+
+        request(
+            &guild,
+            &state,
+            "2018-03-01T10:00:00Z",
+            "2018-03-01T12:00:00Z",
+        );
+
+        let purge = state.guild(guild.id).challenge_code.take_purge().unwrap();
+        assert_eq!(purge.messages.len(), 250);
+    }
+
+    #[test]
+    fn rejects_the_wrong_code() {
+        let guild = FakeGuild::new();
+        let state = State::in_memory();
+        let staff_alert = fake::staff_alert();
+        guild.post(TARGET, UserId(1), "hello", "2018-03-01T11:00:00Z");
+        issue(&state, &guild, Utc::now().timestamp());
+
+        let msg = guild.post(staff_alert.admin_channel, ADMIN, "", "2018-03-01T12:00:00Z");
+        let cmd = Command::PurgeChannel(
+            TARGET,
+            Range::Between(
+                fake::time("2018-03-01T10:00:00Z"),
+                fake::time("2018-03-01T12:00:00Z"),
+            ),
+            Vec::new(),
+            "tnotthecode".into(),
+        );
+        purge_channel_with(&guild, &state, &staff_alert, &msg, &cmd);
+
+        assert!(state.guild(guild.id).challenge_code.take_purge().is_none());
+        assert!(guild
+            .last_message(staff_alert.admin_channel)
+            .unwrap()
+            .contains("Invalid challenge code"));
+    }
+
+    #[test]
+    fn only_works_in_the_admin_channel() {
+        let guild = FakeGuild::new();
+        let state = State::in_memory();
+        let staff_alert = fake::staff_alert();
+        let code = issue(&state, &guild, Utc::now().timestamp());
+
+        let msg = guild.post(TARGET, ADMIN, "purge", "2018-03-01T12:00:00Z");
+        let cmd = Command::PurgeChannel(
+            TARGET,
+            Range::Between(
+                fake::time("2018-03-01T10:00:00Z"),
+                fake::time("2018-03-01T13:00:00Z"),
+            ),
+            Vec::new(),
+            code,
+        );
+        purge_channel_with(&guild, &state, &staff_alert, &msg, &cmd);
+
+        assert!(guild.contents(TARGET).is_empty());
+        assert!(state.guild(guild.id).challenge_code.take_purge().is_none());
+        assert!(guild
+            .last_message(staff_alert.admin_channel)
+            .unwrap()
+            .contains("do that in here"));
+    }
+
+    #[test]
+    fn deletes_what_was_gathered() {
+        let guild = FakeGuild::new();
+        let state = State::in_memory();
+        let staff_alert = fake::staff_alert();
+        guild.post(TARGET, UserId(1), "keep", "2018-03-01T09:00:00Z");
+        spam(&guild, 150);
+        let msg = request(
+            &guild,
+            &state,
+            "2018-03-01T10:00:00Z",
+            "2018-03-01T12:00:00Z",
+        );
+
+        execute_purge_with(
+            &guild,
+            &state,
+            &staff_alert,
+            &msg,
+            &Command::ExecutePurge(150),
+        );
+
+        assert_eq!(guild.contents(TARGET), vec!["keep"]);
+    }
+
+    #[test]
+    fn deletes_old_messages_one_at_a_time() {
+        let guild = FakeGuild::new();
+        let state = State::in_memory();
+        let staff_alert = fake::staff_alert();
+        spam(&guild, 150);
+        let msg = request(
+            &guild,
+            &state,
+            "2018-03-01T10:00:00Z",
+            "2018-03-01T12:00:00Z",
+        );
+
+        guild.set_time("2018-04-01T12:00:00Z");
+        execute_purge_with(
+            &guild,
+            &state,
+            &staff_alert,
+            &msg,
+            &Command::ExecutePurge(150),
+        );
+
+        assert!(guild.contents(TARGET).is_empty());
+        assert!(guild.logs().is_empty());
+    }
+
+    #[test]
+    fn needs_the_right_count_to_delete() {
+        let guild = FakeGuild::new();
+        let state = State::in_memory();
+        let staff_alert = fake::staff_alert();
+        guild.post(TARGET, UserId(1), "spam", "2018-03-01T11:00:00Z");
+        let msg = request(
+            &guild,
+            &state,
+            "2018-03-01T10:00:00Z",
+            "2018-03-01T12:00:00Z",
+        );
+
+        execute_purge_with(
+            &guild,
+            &state,
+            &staff_alert,
+            &msg,
+            &Command::ExecutePurge(5),
+        );
+
+        assert_eq!(guild.contents(TARGET), vec!["spam"]);
+    }
+
+    #[test]
+    fn deleting_picks_up_after_a_restart() {
+        let guild = FakeGuild::new();
+        let state = State::in_memory();
+        let staff_alert = fake::staff_alert();
+        guild.post(TARGET, UserId(1), "keep", "2018-03-01T09:00:00Z");
+        spam(&guild, 150);
+        request(
+            &guild,
+            &state,
+            "2018-03-01T10:00:00Z",
+            "2018-03-01T12:00:00Z",
+        );
+
+        // As if the bot stopped right after the first chunk
+        let code = &state.guild(guild.id).challenge_code;
+        let first = code.purge().unwrap().messages[..100].to_vec();
+        guild.delete_messages(TARGET, &first).unwrap();
+        code.update_purge(|purge| {
+            purge.stage = Stage::Deleting;
+            purge.deleted = 100;
+        });
+        state.save_purge(guild.id).unwrap();
+
+        let reloaded = state.reload();
+        resume_purge(&guild, &reloaded, &staff_alert, guild.id);
+
+        assert_eq!(guild.contents(TARGET), vec!["keep"]);
+        assert!(reloaded.guild(guild.id).challenge_code.purge().is_none());
+        assert!(reloaded
+            .reload()
+            .guild(guild.id)
+            .challenge_code
+            .purge()
+            .is_none());
+        assert!(guild.logs().is_empty());
+    }
+
+    #[test]
+    fn an_old_purge_leaves_a_newer_one_alone() {
+        let guild = FakeGuild::new();
+        let state = State::in_memory();
+        spam(&guild, 150);
+        request(
+            &guild,
+            &state,
+            "2018-03-01T10:00:00Z",
+            "2018-03-01T12:00:00Z",
+        );
+
+        // Purge request #1 got cancelled while it was still going, and #2
+        // started in its place
+        let code = &state.guild(guild.id).challenge_code;
+        let old = code.take_purge().unwrap();
+        request(
+            &guild,
+            &state,
+            "2018-03-01T11:00:00Z",
+            "2018-03-01T12:00:00Z",
+        );
+        delete_purge(&guild, &state, &fake::staff_alert(), guild.id, old);
+
+        let purge = code.purge().unwrap();
+        assert_eq!(purge.number, 2);
+        assert_eq!(purge.stage, Stage::Confirming);
+        assert_eq!(purge.deleted, 0);
+    }
+
+    #[test]
+    fn gathering_starts_over_after_a_restart() {
+        let guild = FakeGuild::new();
+        let state = State::in_memory();
+        let staff_alert = fake::staff_alert();
+        state.guild(guild.id).challenge_code.set_purge(Some(Purge {
+            number: 1,
+            channel: TARGET,
+            requested_by: ADMIN,
+            stage: Stage::Gathering,
+            messages: Vec::new(),
+            deleted: 0,
+        }));
+        state.save_purge(guild.id).unwrap();
+
+        let reloaded = state.reload();
+        resume_purge(&guild, &reloaded, &staff_alert, guild.id);
+
+        assert!(reloaded.guild(guild.id).challenge_code.purge().is_none());
+        assert!(guild
+            .last_message(staff_alert.admin_channel)
+            .unwrap()
+            .contains("nothing was deleted"));
+    }
+
+    #[test]
+    fn one_purge_at_a_time() {
+        let guild = FakeGuild::new();
+        let state = State::in_memory();
+        guild.post(TARGET, UserId(1), "spam", "2018-03-01T11:00:00Z");
+        request(
+            &guild,
+            &state,
+            "2018-03-01T10:00:00Z",
+            "2018-03-01T12:00:00Z",
+        );
+        state
+            .guild(guild.id)
+            .challenge_code
+            .update_purge(|purge| purge.stage = Stage::Deleting);
+
+        request(
+            &guild,
+            &state,
+            "2018-03-01T10:00:00Z",
+            "2018-03-01T12:00:00Z",
+        );
+
+        assert!(guild
+            .last_message(fake::staff_alert().admin_channel)
+            .unwrap()
+            .contains("already a purge going"));
+        let purge = state.guild(guild.id).challenge_code.purge().unwrap();
+        assert_eq!(purge.stage, Stage::Deleting);
+    }
+
+    #[test]
+    fn codes_only_work_for_whoever_asked() {
+        let guild = FakeGuild::new();
+        let state = State::in_memory();
+        let staff_alert = fake::staff_alert();
+        let code = issue(&state, &guild, Utc::now().timestamp());
+
+        let other = UserId(101);
+        let msg = guild.post(staff_alert.admin_channel, other, "", "2018-03-01T12:00:00Z");
+        let range = Range::Between(
+            fake::time("2018-03-01T10:00:00Z"),
+            fake::time("2018-03-01T12:00:00Z"),
+        );
+        let cmd = Command::PurgeChannel(TARGET, range, Vec::new(), code);
+        purge_channel_with(&guild, &state, &staff_alert, &msg, &cmd);
+
+        assert!(state.guild(guild.id).challenge_code.purge().is_none());
+        assert!(guild
+            .last_message(staff_alert.admin_channel)
+            .unwrap()
+            .contains("issued to someone else"));
+        assert_eq!(state.guild(guild.id).challenge_code.log().len(), 1);
+
+        // It still works for the admin it's for
+        let msg = guild.post(staff_alert.admin_channel, ADMIN, "", "2018-03-01T12:00:00Z");
+        purge_channel_with(&guild, &state, &staff_alert, &msg, &cmd);
+        let log = state.reload().guild(guild.id).challenge_code.log();
+        assert_eq!(log[1].event, Event::Used);
+        assert_eq!(log[1].by, Some(ADMIN));
+    }
+
+    #[test]
+    fn codes_expire() {
+        let guild = FakeGuild::new();
+        let state = State::in_memory();
+        let staff_alert = fake::staff_alert();
+        guild.post(TARGET, UserId(1), "hello", "2018-03-01T11:00:00Z");
+        let issued = Utc::now().timestamp() - DEFAULT_EXPIRY as i64 - 1;
+        let code = issue(&state, &guild, issued);
+
+        let msg = guild.post(staff_alert.admin_channel, ADMIN, "", "2018-03-01T12:00:00Z");
+        let range = Range::Between(
+            fake::time("2018-03-01T10:00:00Z"),
+            fake::time("2018-03-01T12:00:00Z"),
+        );
+        let cmd = Command::PurgeChannel(TARGET, range, Vec::new(), code);
+        purge_channel_with(&guild, &state, &staff_alert, &msg, &cmd);
+
+        assert!(state.guild(guild.id).challenge_code.purge().is_none());
+        assert!(guild
+            .last_message(staff_alert.admin_channel)
+            .unwrap()
+            .contains("expired"));
+        let log = state.guild(guild.id).challenge_code.log();
+        assert_eq!(log[1].event, Event::Expired);
+        assert_eq!(log[1].at, issued + DEFAULT_EXPIRY as i64);
+    }
+
+    #[test]
+    fn issuing_pings_whoever_it_is_set_to() {
+        let guild = FakeGuild::new();
+        let state = State::in_memory();
+        let staff_alert = fake::staff_alert();
+        let msg = guild.post(staff_alert.admin_channel, ADMIN, "", "2018-03-01T12:00:00Z");
+        let cmd = Command::ChallengeCode(Action::Purge);
+
+        issue_code_with(&guild, &state, &staff_alert, &msg, &cmd);
+        let issued = guild.last_message(staff_alert.admin_channel).unwrap();
+        assert!(issued.contains("for the next 5 minutes. (@everyone)"));
+
+        set_code_setting_with(&guild, &state, &msg, &Command::SetChallengeExpiry(600));
+        set_code_setting_with(
+            &guild,
+            &state,
+            &msg,
+            &Command::SetChallengePing(Ping::Nobody),
+        );
+        issue_code_with(&guild, &state, &staff_alert, &msg, &cmd);
+        let issued = guild.last_message(staff_alert.admin_channel).unwrap();
+        assert!(issued.ends_with("for the next 10 minutes."));
+
+        let challenge_code = &state.reload().guild(guild.id).challenge_code;
+        assert_eq!(challenge_code.expiry(), 600);
+        assert_eq!(challenge_code.ping(), Ping::Nobody);
+    }
+
+    #[test]
+    fn lists_what_happened_to_codes() {
+        let guild = FakeGuild::new();
+        let state = State::in_memory();
+        let staff_alert = fake::staff_alert();
+        guild.post(TARGET, UserId(1), "spam", "2018-03-01T11:00:00Z");
+        let msg = request(
+            &guild,
+            &state,
+            "2018-03-01T10:00:00Z",
+            "2018-03-01T12:00:00Z",
+        );
+
+        list_codes_with(
+            &guild,
+            &state,
+            &staff_alert,
+            &msg,
+            &Command::ListChallengeCodes,
+        );
+
+        let list = guild.last_message(staff_alert.admin_channel).unwrap();
+        let used = list.find("purge code was used").unwrap();
+        let issued = list.find("purge code was issued").unwrap();
+        assert!(used < issued);
+    }
+
+    #[test]
+    fn lists_codes_that_expired_unused() {
+        let guild = FakeGuild::new();
+        let state = State::in_memory();
+        let staff_alert = fake::staff_alert();
+        issue(
+            &state,
+            &guild,
+            Utc::now().timestamp() - DEFAULT_EXPIRY as i64 - 1,
+        );
+
+        let msg = guild.post(staff_alert.admin_channel, ADMIN, "", "2018-03-01T12:00:00Z");
+        list_codes_with(
+            &guild,
+            &state,
+            &staff_alert,
+            &msg,
+            &Command::ListChallengeCodes,
+        );
+
+        let list = guild.last_message(staff_alert.admin_channel).unwrap();
+        assert!(list.contains("purge code was expired"));
+        let log = state.reload().guild(guild.id).challenge_code.log();
+        assert_eq!(log[1].event, Event::Expired);
+    }
+
+    #[test]
+    fn two_person_rule_needs_a_second_admin() {
+        let guild = FakeGuild::new();
+        let state = State::in_memory();
+        let staff_alert = fake::staff_alert();
+        guild.post(TARGET, UserId(1), "keep", "2018-03-01T09:00:00Z");
+        guild.post(TARGET, UserId(1), "spam", "2018-03-01T11:00:00Z");
+        let msg = request(
+            &guild,
+            &state,
+            "2018-03-01T10:00:00Z",
+            "2018-03-01T12:00:00Z",
+        );
+        set_code_setting_with(&guild, &state, &msg, &Command::SetTwoPersonRule(true));
+
+        execute_purge_with(
+            &guild,
+            &state,
+            &staff_alert,
+            &msg,
+            &Command::ExecutePurge(1),
+        );
+        assert!(guild
+            .last_message(staff_alert.admin_channel)
+            .unwrap()
+            .contains("I confirm purge request #1"));
+
+        let confirm = |author: UserId, number: u32| {
+            let msg = guild.post(
+                staff_alert.admin_channel,
+                author,
+                "",
+                "2018-03-01T12:01:00Z",
+            );
+            let cmd = Command::ConfirmAction(Action::Purge, number);
+            confirm_purge_with(&guild, &state, &staff_alert, &msg, &cmd);
+        };
+
+        confirm(ADMIN, 1);
+        assert!(guild
+            .last_message(staff_alert.admin_channel)
+            .unwrap()
+            .contains("different admin"));
+        confirm(UserId(101), 2);
+        assert!(guild
+            .last_message(staff_alert.admin_channel)
+            .unwrap()
+            .contains("#2 isn't waiting"));
+        assert_eq!(guild.contents(TARGET), vec!["keep", "spam"]);
+
+        confirm(UserId(101), 1);
+        assert_eq!(guild.contents(TARGET), vec!["keep"]);
+        assert!(state.guild(guild.id).challenge_code.purge().is_none());
+
+        // Confirming again doesn't start it over
+        confirm(UserId(102), 1);
+        assert!(guild
+            .last_message(staff_alert.admin_channel)
+            .unwrap()
+            .contains("#1 isn't waiting"));
+    }
+
+    #[test]
+    fn turning_off_the_two_person_rule_takes_two_admins() {
+        let guild = FakeGuild::new();
+        let state = State::in_memory();
+        let staff_alert = fake::staff_alert();
+        let set = |author: UserId, on: bool| {
+            let msg = guild.post(
+                staff_alert.admin_channel,
+                author,
+                "",
+                "2018-03-01T12:00:00Z",
+            );
+            set_code_setting_with(&guild, &state, &msg, &Command::SetTwoPersonRule(on));
+            msg
+        };
+        let rule = || state.guild(guild.id).challenge_code.two_person_rule();
+
+        set(ADMIN, true);
+        assert!(rule());
+        set(ADMIN, false);
+        assert!(rule());
+        set(ADMIN, false);
+        assert!(guild
+            .last_message(staff_alert.admin_channel)
+            .unwrap()
+            .contains("different admin"));
+        assert!(rule());
+
+        let msg = set(UserId(101), false);
+        assert!(!rule());
+        let log = state.reload().guild(guild.id).challenge_code.log();
+        assert_eq!(log[1].event, Event::TwoPersonRuleOff);
+        assert!(guild
+            .logs()
+            .iter()
+            .any(|log| log.contains("asked to turn off")));
+
+        list_codes_with(
+            &guild,
+            &state,
+            &staff_alert,
+            &msg,
+            &Command::ListChallengeCodes,
+        );
+        let list = guild.last_message(staff_alert.admin_channel).unwrap();
+        assert!(list.contains("<@100> and <@101> turned off the two person rule"));
+        assert!(list.contains("<@100> turned on the two person rule"));
+    }
+
+    #[test]
+    fn asking_to_turn_off_the_two_person_rule_expires() {
+        let code = state::challenge::Code::default();
+        let other = UserId(101);
+        code.set_two_person_rule(true, ADMIN, 0);
+
+        assert_eq!(
+            code.set_two_person_rule(false, ADMIN, 0),
+            RuleChange::Requested
+        );
+        let later = DEFAULT_EXPIRY as i64;
+        assert_eq!(
+            code.set_two_person_rule(false, other, later),
+            RuleChange::Requested
+        );
+        assert!(code.two_person_rule());
+        assert_eq!(
+            code.set_two_person_rule(false, ADMIN, later + 1),
+            RuleChange::Changed
+        );
+        assert!(!code.two_person_rule());
+    }
+}
@@ -1,7 +1,10 @@
 use serenity::model::prelude::*;
 use serenity::prelude::*;
 
+use discord::{DiscordApi, MessageRef, SerenityApi};
 use grammar::ast::Command;
+use state::guild::GuildState;
+use state::State;
 
 pub fn scan_roles(ctx: &Context, msg: &Message, cmd: &Command) {
     let (high, low) = match cmd {
@@ -19,11 +22,16 @@ pub fn scan_roles(ctx: &Context, msg: &Message, cmd: &Command) {
     };
 
     let state = ::state(ctx);
-    let result = state.roles.rescan(&guild, high, low);
+    let guild_state = state.guild(guild.id);
+    let result = guild_state.roles.rescan(&guild, high, low);
     match result {
         Ok(_) => {
-            let _ = state.save();
-            let taglist = state.roles.all_role_tags().collect::<Vec<_>>().join(", ");
+            let _ = state.save_guild(guild.id);
+            let taglist = guild_state
+                .roles
+                .all_role_tags()
+                .collect::<Vec<_>>()
+                .join(", ");
             let _ = msg.reply(&format!("Done! New role list: {}", taglist));
         }
         Err(e) => {
@@ -41,9 +49,16 @@ pub fn alias_role(ctx: &Context, msg: &Message, cmd: &Command) {
 
     let (alias, target) = (role_tag(&alias), role_tag(&target));
 
+    let guild = msg.guild_id().unwrap();
     let state = ::state(ctx);
-    state.roles.aliases.write().unwrap().insert(alias, target);
-    let _ = state.save();
+    state
+        .guild(guild)
+        .roles
+        .aliases
+        .write()
+        .unwrap()
+        .insert(alias.clone(), target);
+    let _ = state.save_alias(guild, &alias);
 
     let _ = msg.react("\u{1F44D}");
 }
@@ -56,15 +71,22 @@ pub fn remove_alias(ctx: &Context, msg: &Message, cmd: &Command) {
 
     let alias = role_tag(&alias);
 
+    let guild = msg.guild_id().unwrap();
     let state = ::state(ctx);
-    state.roles.aliases.write().unwrap().remove(&alias);
-    let _ = state.save();
+    state
+        .guild(guild)
+        .roles
+        .aliases
+        .write()
+        .unwrap()
+        .remove(&alias);
+    let _ = state.save_alias(guild, &alias);
 
     let _ = msg.react("\u{1F44D}");
 }
 
 pub fn list_roles(ctx: &Context, msg: &Message, _cmd: &Command) {
-    let state = ::state(ctx);
+    let state = ::state(ctx).guild(msg.guild_id().unwrap());
 
     let mut roles: Vec<_> = state
         .roles
@@ -85,7 +107,7 @@ pub fn list_roles(ctx: &Context, msg: &Message, _cmd: &Command) {
 }
 
 pub fn list_aliases(ctx: &Context, msg: &Message, _cmd: &Command) {
-    let state = ::state(ctx);
+    let state = ::state(ctx).guild(msg.guild_id().unwrap());
 
     let mut aliases: Vec<_> = state
         .roles
@@ -105,8 +127,7 @@ pub fn list_aliases(ctx: &Context, msg: &Message, _cmd: &Command) {
     let _ = msg.reply(&buf);
 }
 
-fn parse_roles(ctx: &Context, roles: &[String]) -> (Vec<RoleId>, Vec<String>) {
-    let state = ::state(ctx);
+fn parse_roles(state: &GuildState, roles: &[String]) -> (Vec<RoleId>, Vec<String>) {
     let all_roles = state.roles.all_roles();
     let all_aliases = state.roles.all_aliases();
 
@@ -128,89 +149,83 @@ fn parse_roles(ctx: &Context, roles: &[String]) -> (Vec<RoleId>, Vec<String>) {
 }
 
 pub fn give_roles(ctx: &Context, msg: &Message, cmd: &Command) {
+    give_roles_with(&SerenityApi::new(ctx), &::state(ctx), &msg.into(), cmd);
+}
+
+pub fn give_roles_with(api: &DiscordApi, state: &State, msg: &MessageRef, cmd: &Command) {
     let (target, roles) = match cmd {
         Command::GiveRoles { target, roles } => (*target, roles),
         _ => unreachable!(),
     };
 
-    let mut target = match msg.guild_id().unwrap().member(target) {
-        Ok(target) => target,
-        Err(e) => {
-            ::log(
-                ctx,
-                &format!(
-                    "Wowzers! Error setting {}'s roles:\n{:?}",
-                    msg.author.mention(),
-                    e
-                ),
-            );
-            let _ = msg.reply("Oops, something went wrong :( Ask a mod about it~");
-            return;
-        }
+    let ids = match check_roles(api, state, msg, target, roles) {
+        Some(ids) => ids,
+        None => return,
     };
 
-    if roles.len() < 1 {
-        let _ = msg.reply("You didn't list any roles...");
-        return;
-    }
-
-    let (ids, cant_find) = parse_roles(ctx, roles);
-
-    if cant_find.len() > 0 {
-        let list = cant_find.join(", ");
-        let _ = msg.reply(&format!(
-            "I'm sorry, I couldn't find the following \
-             roles. Make sure you spelled them right: {}",
-            list
-        ));
-        return;
-    }
-
-    let _ = target.add_roles(&ids);
-    let _ = msg.react("\u{1F44D}");
+    let _ = api.add_roles(msg.guild.unwrap(), target, &ids);
+    let _ = api.react(msg.channel, msg.id, "\u{1F44D}");
 }
 
 pub fn take_roles(ctx: &Context, msg: &Message, cmd: &Command) {
+    take_roles_with(&SerenityApi::new(ctx), &::state(ctx), &msg.into(), cmd);
+}
+
+pub fn take_roles_with(api: &DiscordApi, state: &State, msg: &MessageRef, cmd: &Command) {
     let (target, roles) = match cmd {
         Command::TakeRoles { target, roles } => (*target, roles),
         _ => unreachable!(),
     };
 
-    let mut target = match msg.guild_id().unwrap().member(target) {
-        Ok(target) => target,
-        Err(e) => {
-            ::log(
-                ctx,
-                &format!(
-                    "Wowzers! Error setting {}'s roles:\n{:?}",
-                    msg.author.mention(),
-                    e
-                ),
-            );
-            let _ = msg.reply("Oops, something went wrong :( Ask a mod about it~");
-            return;
-        }
+    let ids = match check_roles(api, state, msg, target, roles) {
+        Some(ids) => ids,
+        None => return,
     };
 
+    let _ = api.remove_roles(msg.guild.unwrap(), target, &ids);
+    let _ = api.react(msg.channel, msg.id, "\u{1F44D}");
+}
+
+/// Makes sure the target is around and all the roles exist, and tells the
+/// user what's wrong if not
+fn check_roles(
+    api: &DiscordApi,
+    state: &State,
+    msg: &MessageRef,
+    target: UserId,
+    roles: &[String],
+) -> Option<Vec<RoleId>> {
+    if let Err(e) = api.member_roles(msg.guild.unwrap(), target) {
+        api.log(&format!(
+            "Wowzers! Error setting {}'s roles:\n{:?}",
+            msg.author.mention(),
+            e
+        ));
+        let _ = api.reply(msg, "Oops, something went wrong :( Ask a mod about it~");
+        return None;
+    }
+
     if roles.len() < 1 {
-        let _ = msg.reply("You didn't list any roles...");
-        return;
+        let _ = api.reply(msg, "You didn't list any roles...");
+        return None;
     }
 
-    let (ids, cant_find) = parse_roles(ctx, roles);
+    let (ids, cant_find) = parse_roles(&state.guild(msg.guild.unwrap()), roles);
 
     if cant_find.len() > 0 {
         let list = cant_find.join(", ");
-        let _ = msg.reply(&format!(
-            "I'm sorry, I couldn't find the following \
-             roles. Make sure you spelled them right: {}",
-            list
-        ));
-        return;
+        let _ = api.reply(
+            msg,
+            &format!(
+                "I'm sorry, I couldn't find the following \
+                 roles. Make sure you spelled them right: {}",
+                list
+            ),
+        );
+        return None;
     }
 
-    let _ = target.remove_roles(&ids);
-    let _ = msg.react("\u{1F44D}");
+    Some(ids)
 }
 
 fn role_tag(role: &str) -> String {
@@ -219,3 +234,84 @@ fn role_tag(role: &str) -> String {
         .map(|c| if c == ' ' { '-' } else { c })
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use discord::fake::FakeGuild;
+
+    const CHANNEL: ChannelId = ChannelId(10);
+    const MEMBER: UserId = UserId(100);
+
+    fn setup() -> (FakeGuild, State) {
+        let guild = FakeGuild::new();
+        guild.add_member(MEMBER, &[RoleId(1)]);
+
+        let state = State::in_memory();
+        let roles = &state.guild(guild.id).roles;
+        {
+            let mut roles = roles.roles.write().unwrap();
+            roles.insert("artist".into(), RoleId(20));
+            roles.insert("gamer".into(), RoleId(21));
+        }
+        roles
+            .aliases
+            .write()
+            .unwrap()
+            .insert("games".into(), "gamer".into());
+
+        (guild, state)
+    }
+
+    #[test]
+    fn gives_roles_by_name_or_alias() {
+        let (guild, state) = setup();
+        let msg = guild.post(CHANNEL, MEMBER, "", "2018-03-01T12:00:00Z");
+        let cmd = Command::give_roles(MEMBER, &["artist", "games"]);
+
+        give_roles_with(&guild, &state, &msg, &cmd);
+
+        assert_eq!(guild.roles(MEMBER), vec![RoleId(1), RoleId(20), RoleId(21)]);
+        assert_eq!(guild.reactions(msg.id), vec!["\u{1F44D}"]);
+    }
+
+    #[test]
+    fn gives_nothing_if_a_role_is_missing() {
+        let (guild, state) = setup();
+        let msg = guild.post(CHANNEL, MEMBER, "", "2018-03-01T12:00:00Z");
+        let cmd = Command::give_roles(MEMBER, &["artist", "wizard"]);
+
+        give_roles_with(&guild, &state, &msg, &cmd);
+
+        assert_eq!(guild.roles(MEMBER), vec![RoleId(1)]);
+        assert!(guild.last_message(CHANNEL).unwrap().ends_with("wizard"));
+        assert!(guild.reactions(msg.id).is_empty());
+    }
+
+    #[test]
+    fn complains_about_unknown_members() {
+        let (guild, state) = setup();
+        let msg = guild.post(CHANNEL, MEMBER, "", "2018-03-01T12:00:00Z");
+        let cmd = Command::give_roles(UserId(999), &["artist"]);
+
+        give_roles_with(&guild, &state, &msg, &cmd);
+
+        assert_eq!(guild.logs().len(), 1);
+        assert!(guild
+            .last_message(CHANNEL)
+            .unwrap()
+            .contains("something went wrong"));
+    }
+
+    #[test]
+    fn takes_roles() {
+        let (guild, state) = setup();
+        guild.add_member(MEMBER, &[RoleId(1), RoleId(20), RoleId(21)]);
+        let msg = guild.post(CHANNEL, MEMBER, "", "2018-03-01T12:00:00Z");
+        let cmd = Command::take_roles(MEMBER, &["games"]);
+
+        take_roles_with(&guild, &state, &msg, &cmd);
+
+        assert_eq!(guild.roles(MEMBER), vec![RoleId(1), RoleId(20)]);
+    }
+}
@@ -0,0 +1,201 @@
+use serenity::model::prelude::*;
+use serenity::prelude::*;
+
+use std::fmt::Write;
+
+use commands::ephemeral;
+use discord::{DiscordApi, MessageRef, SerenityApi};
+use grammar::ast::Command;
+use state::challenge::Ping;
+use state::State;
+use StaffAlertData;
+
+pub fn set_staff_setting(ctx: &Context, msg: &Message, cmd: &Command) {
+    set_staff_setting_with(&SerenityApi::new(ctx), &::state(ctx), &msg.into(), cmd);
+}
+
+pub fn set_staff_setting_with(api: &DiscordApi, state: &State, msg: &MessageRef, cmd: &Command) {
+    let guild = msg.guild.unwrap();
+    let mut staff_alert = match state.guild(guild).staff_alert() {
+        Some(staff_alert) => StaffAlertData::clone(&staff_alert),
+        None => {
+            api.log("Oof, there aren't any staff settings for this guild???");
+            let _ = api.reply(msg, "Oops, something went wrong :( Ask a mod about it~");
+            return;
+        }
+    };
+
+    let (name, found) = match cmd {
+        Command::SetStaffChannel(name, channel) => (
+            name,
+            channel_setting(&mut staff_alert, name)
+                .map(|setting| *setting = *channel)
+                .is_some(),
+        ),
+        Command::SetStaffRole(name, role) => (
+            name,
+            role_setting(&mut staff_alert, name)
+                .map(|setting| *setting = *role)
+                .is_some(),
+        ),
+        _ => return,
+    };
+
+    if !found {
+        let _ = api.reply(
+            msg,
+            &format!(
+                "I don't know a setting called `{}`. I can set the admin channel, \
+                 mod channel, front door, void, anon feedback channel, feedback log \
+                 and mod call role.",
+                name
+            ),
+        );
+        return;
+    }
+
+    state.guild(guild).set_staff_alert(staff_alert);
+    if let Err(e) = state.save_staff_alert(guild) {
+        api.log(&format!("Couldn't save the staff settings: {}", e));
+        let _ = api.reply(msg, "Oops, something went wrong :( Ask a mod about it~");
+        return;
+    }
+
+    let _ = api.react(msg.channel, msg.id, "\u{1F44D}");
+}
+
+pub fn list_settings(ctx: &Context, msg: &Message, _cmd: &Command) {
+    let staff_alert = match msg.guild_id().and_then(|guild| ::staff_alert(ctx, guild)) {
+        Some(staff_alert) => staff_alert,
+        None => return,
+    };
+
+    // Mentioning a role would ping everyone in it, so they go by name
+    let role_name = |id: RoleId| {
+        msg.guild()
+            .and_then(|guild| guild.read().roles.get(&id).map(|role| role.name.clone()))
+            .unwrap_or_else(|| id.0.to_string())
+    };
+    let mod_call = role_name(staff_alert.mod_call);
+
+    let mut reply = String::from("Staff settings:");
+    let channels = [
+        ("admin channel", staff_alert.admin_channel),
+        ("mod channel", staff_alert.mod_channel),
+        ("front door", staff_alert.front_door),
+        ("void", staff_alert.the_void),
+        ("anon feedback channel", staff_alert.anon_feedback),
+        ("feedback log", staff_alert.feedback_log),
+    ];
+    for &(name, channel) in &channels {
+        let _ = write!(reply, "\n{}: {}", name, channel.mention());
+    }
+    let _ = write!(reply, "\nmod call role: {}", mod_call);
+
+    let state = ::state(ctx);
+    let guild = state.guild(msg.guild_id().unwrap());
+    let ping = match guild.challenge_code.ping() {
+        Ping::Everyone => "everyone".to_string(),
+        Ping::Role(role) => format!("the {} role", role_name(role)),
+        Ping::Nobody => "nobody".to_string(),
+    };
+    let _ = write!(
+        reply,
+        "\nchallenge codes: last {} and ping {}",
+        ephemeral::describe_ttl(guild.challenge_code.expiry()),
+        ping
+    );
+    if guild.challenge_code.two_person_rule() {
+        reply.push_str("\ntwo person rule: on, purges need a second admin");
+    }
+
+    for (channel, ttl) in guild.ephemeral.all() {
+        let _ = write!(
+            reply,
+            "\n{} deletes messages after {}",
+            channel.mention(),
+            ephemeral::describe_ttl(ttl)
+        );
+    }
+
+    ::logres(ctx, msg.reply(&reply));
+}
+
+fn channel_setting<'a>(
+    staff_alert: &'a mut StaffAlertData,
+    name: &str,
+) -> Option<&'a mut ChannelId> {
+    match name {
+        "admin" => Some(&mut staff_alert.admin_channel),
+        "mod" => Some(&mut staff_alert.mod_channel),
+        "front door" => Some(&mut staff_alert.front_door),
+        "void" => Some(&mut staff_alert.the_void),
+        "anon feedback" | "anonymous feedback" => Some(&mut staff_alert.anon_feedback),
+        "feedback log" => Some(&mut staff_alert.feedback_log),
+        _ => None,
+    }
+}
+
+fn role_setting<'a>(staff_alert: &'a mut StaffAlertData, name: &str) -> Option<&'a mut RoleId> {
+    match name {
+        "mod call" | "mod ping" => Some(&mut staff_alert.mod_call),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use discord::fake::{self, FakeGuild};
+
+    const ADMIN: UserId = UserId(100);
+
+    fn setup() -> (FakeGuild, State) {
+        let guild = FakeGuild::new();
+        let state = State::in_memory();
+        state.guild(guild.id).set_staff_alert(fake::staff_alert());
+        (guild, state)
+    }
+
+    #[test]
+    fn changes_one_channel() {
+        let (guild, state) = setup();
+        let msg = guild.post(ChannelId(2), ADMIN, "", "2018-03-01T12:00:00Z");
+        let cmd = Command::SetStaffChannel("mod".into(), ChannelId(30));
+
+        set_staff_setting_with(&guild, &state, &msg, &cmd);
+
+        let staff_alert = state.guild(guild.id).staff_alert().unwrap();
+        assert_eq!(staff_alert.mod_channel, ChannelId(30));
+        assert_eq!(staff_alert.admin_channel, ChannelId(2));
+        assert_eq!(guild.reactions(msg.id), vec!["\u{1F44D}"]);
+    }
+
+    #[test]
+    fn changes_the_mod_call_role() {
+        let (guild, state) = setup();
+        let msg = guild.post(ChannelId(2), ADMIN, "", "2018-03-01T12:00:00Z");
+        let cmd = Command::SetStaffRole("mod call".into(), RoleId(40));
+
+        set_staff_setting_with(&guild, &state, &msg, &cmd);
+
+        let staff_alert = state.guild(guild.id).staff_alert().unwrap();
+        assert_eq!(staff_alert.mod_call, RoleId(40));
+    }
+
+    #[test]
+    fn complains_about_unknown_settings() {
+        let (guild, state) = setup();
+        let msg = guild.post(ChannelId(2), ADMIN, "", "2018-03-01T12:00:00Z");
+        let cmd = Command::SetStaffChannel("moderator lounge".into(), ChannelId(30));
+
+        set_staff_setting_with(&guild, &state, &msg, &cmd);
+
+        let staff_alert = state.guild(guild.id).staff_alert().unwrap();
+        assert_eq!(staff_alert.mod_channel, ChannelId(3));
+        assert!(guild
+            .last_message(ChannelId(2))
+            .unwrap()
+            .contains("`moderator lounge`"));
+    }
+}
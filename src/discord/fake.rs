@@ -0,0 +1,300 @@
+//! An in-memory guild for testing commands without talking to Discord
+
+use chrono::{DateTime, FixedOffset};
+use serenity::model::prelude::*;
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use super::{ApiError, ChannelMessage, DiscordApi, MessageRef};
+use StaffAlertData;
+
+pub struct FakeGuild {
+    pub id: GuildId,
+    members: RefCell<HashMap<UserId, Vec<RoleId>>>,
+    /// Every channel's messages, oldest first
+    channels: RefCell<HashMap<ChannelId, Vec<ChannelMessage>>>,
+    reactions: RefCell<Vec<(MessageId, String)>>,
+    dms: RefCell<Vec<(UserId, String)>>,
+    logs: RefCell<Vec<String>>,
+    next_id: Cell<u64>,
+    /// What time Discord thinks it is, for the bulk delete age limit
+    now: Cell<Option<i64>>,
+    /// How many more deletes get rate limited
+    rate_limits: Cell<u32>,
+}
+
+impl FakeGuild {
+    pub fn new() -> FakeGuild {
+        FakeGuild {
+            id: GuildId(1),
+            members: RefCell::new(HashMap::new()),
+            channels: RefCell::new(HashMap::new()),
+            reactions: RefCell::new(Vec::new()),
+            dms: RefCell::new(Vec::new()),
+            logs: RefCell::new(Vec::new()),
+            next_id: Cell::new(1000),
+            now: Cell::new(None),
+            rate_limits: Cell::new(0),
+        }
+    }
+
+    pub fn add_member(&self, user: UserId, roles: &[RoleId]) {
+        self.members.borrow_mut().insert(user, roles.to_vec());
+    }
+
+    pub fn roles(&self, user: UserId) -> Vec<RoleId> {
+        let mut roles = self.members.borrow()[&user].clone();
+        roles.sort();
+        roles
+    }
+
+    /// Posts a message as someone else, and returns a reference to it as if
+    /// it had just been sent to the bot
+    pub fn post(
+        &self,
+        channel: ChannelId,
+        author: UserId,
+        content: &str,
+        timestamp: &str,
+    ) -> MessageRef {
+        let id = self.next_id();
+        self.channels
+            .borrow_mut()
+            .entry(channel)
+            .or_insert_with(Vec::new)
+            .push(ChannelMessage {
+                id,
+                author,
+                author_tag: format!("user{}#0001", author.0),
+                content: content.to_string(),
+                timestamp: time(timestamp),
+                edited: None,
+                attachments: Vec::new(),
+                pinned: false,
+            });
+        MessageRef {
+            id,
+            channel,
+            guild: Some(self.id),
+            author,
+        }
+    }
+
+    pub fn pin(&self, channel: ChannelId, message: MessageId) {
+        self.change(channel, message, |msg| msg.pinned = true);
+    }
+
+    pub fn edit(&self, channel: ChannelId, message: MessageId, content: &str, timestamp: &str) {
+        self.change(channel, message, |msg| {
+            msg.content = content.to_string();
+            msg.edited = Some(time(timestamp));
+        });
+    }
+
+    pub fn attach(&self, channel: ChannelId, message: MessageId, url: &str) {
+        self.change(channel, message, |msg| {
+            msg.attachments.push(url.to_string())
+        });
+    }
+
+    fn change<F>(&self, channel: ChannelId, message: MessageId, f: F)
+    where
+        F: FnOnce(&mut ChannelMessage),
+    {
+        let mut channels = self.channels.borrow_mut();
+        let msgs = channels.get_mut(&channel).unwrap();
+        if let Some(msg) = msgs.iter_mut().find(|msg| msg.id == message) {
+            f(msg);
+        }
+    }
+
+    /// What's left in the channel, oldest first
+    pub fn contents(&self, channel: ChannelId) -> Vec<String> {
+        self.channels
+            .borrow()
+            .get(&channel)
+            .map(|msgs| msgs.iter().map(|m| m.content.clone()).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn last_message(&self, channel: ChannelId) -> Option<String> {
+        self.contents(channel).pop()
+    }
+
+    pub fn reactions(&self, message: MessageId) -> Vec<String> {
+        self.reactions
+            .borrow()
+            .iter()
+            .filter(|&&(id, _)| id == message)
+            .map(|&(_, ref emoji)| emoji.clone())
+            .collect()
+    }
+
+    /// What the bot has sent to someone in DMs
+    pub fn dms(&self, user: UserId) -> Vec<String> {
+        self.dms
+            .borrow()
+            .iter()
+            .filter(|&&(to, _)| to == user)
+            .map(|&(_, ref content)| content.clone())
+            .collect()
+    }
+
+    pub fn logs(&self) -> Vec<String> {
+        self.logs.borrow().clone()
+    }
+
+    /// Makes bulk deletes refuse anything two weeks older than `timestamp`
+    pub fn set_time(&self, timestamp: &str) {
+        self.now.set(Some(time(timestamp).timestamp()));
+    }
+
+    /// Rate limits the next `deletes` deletes
+    pub fn rate_limit(&self, deletes: u32) {
+        self.rate_limits.set(deletes);
+    }
+
+    fn check_rate_limit(&self) -> Result<(), ApiError> {
+        match self.rate_limits.get() {
+            0 => Ok(()),
+            n => {
+                self.rate_limits.set(n - 1);
+                Err(ApiError::RateLimited(SystemTime::now()))
+            }
+        }
+    }
+
+    fn remove(&self, channel: ChannelId, messages: &[MessageId]) -> Result<(), ApiError> {
+        let mut channels = self.channels.borrow_mut();
+        let msgs = channels
+            .get_mut(&channel)
+            .ok_or_else(|| not_found("channel"))?;
+        msgs.retain(|m| !messages.contains(&m.id));
+        Ok(())
+    }
+
+    fn next_id(&self) -> MessageId {
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+        MessageId(id)
+    }
+}
+
+/// Staff channels and roles with small made up ids
+pub fn staff_alert() -> StaffAlertData {
+    StaffAlertData {
+        admin_channel: ChannelId(2),
+        mod_channel: ChannelId(3),
+        front_door: ChannelId(4),
+        mod_call: RoleId(5),
+        the_void: ChannelId(6),
+        anon_feedback: ChannelId(7),
+        feedback_log: ChannelId(8),
+    }
+}
+
+/// Parses an RFC 3339 timestamp, for setting up tests
+pub fn time(timestamp: &str) -> DateTime<FixedOffset> {
+    DateTime::parse_from_rfc3339(timestamp).unwrap()
+}
+
+fn not_found(what: &str) -> ApiError {
+    ApiError::Other(format!("Unknown {}", what))
+}
+
+impl DiscordApi for FakeGuild {
+    fn say(&self, channel: ChannelId, content: &str) -> Result<MessageId, ApiError> {
+        Ok(self
+            .post(channel, UserId(0), content, "2018-01-01T00:00:00Z")
+            .id)
+    }
+
+    fn dm(&self, user: UserId, content: &str) -> Result<MessageId, ApiError> {
+        if !self.members.borrow().contains_key(&user) {
+            return Err(not_found("member"));
+        }
+        self.dms.borrow_mut().push((user, content.to_string()));
+        Ok(self.next_id())
+    }
+
+    fn react(&self, _: ChannelId, message: MessageId, emoji: &str) -> Result<(), ApiError> {
+        self.reactions
+            .borrow_mut()
+            .push((message, emoji.to_string()));
+        Ok(())
+    }
+
+    fn delete_message(&self, channel: ChannelId, message: MessageId) -> Result<(), ApiError> {
+        self.check_rate_limit()?;
+        self.remove(channel, &[message])
+    }
+
+    fn delete_messages(&self, channel: ChannelId, messages: &[MessageId]) -> Result<(), ApiError> {
+        self.check_rate_limit()?;
+        if messages.len() > 100 {
+            return Err(ApiError::Other("Too many messages to delete".into()));
+        }
+        if let Some(now) = self.now.get() {
+            let too_old = self.channels.borrow().get(&channel).map_or(false, |msgs| {
+                msgs.iter().any(|m| {
+                    messages.contains(&m.id) && now - m.timestamp.timestamp() > 14 * 24 * 60 * 60
+                })
+            });
+            if too_old {
+                return Err(ApiError::Other(
+                    "Can't bulk delete messages older than 2 weeks".into(),
+                ));
+            }
+        }
+        self.remove(channel, messages)
+    }
+
+    fn messages(
+        &self,
+        channel: ChannelId,
+        before: Option<MessageId>,
+        limit: u64,
+    ) -> Result<Vec<ChannelMessage>, ApiError> {
+        let channels = self.channels.borrow();
+        let msgs = channels.get(&channel).ok_or_else(|| not_found("channel"))?;
+        Ok(msgs
+            .iter()
+            .rev()
+            .filter(|m| before.map(|before| m.id < before).unwrap_or(true))
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+
+    fn member_roles(&self, _: GuildId, user: UserId) -> Result<Vec<RoleId>, ApiError> {
+        self.members
+            .borrow()
+            .get(&user)
+            .cloned()
+            .ok_or_else(|| not_found("member"))
+    }
+
+    fn add_roles(&self, _: GuildId, user: UserId, roles: &[RoleId]) -> Result<(), ApiError> {
+        let mut members = self.members.borrow_mut();
+        let member = members.get_mut(&user).ok_or_else(|| not_found("member"))?;
+        for &role in roles {
+            if !member.contains(&role) {
+                member.push(role);
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_roles(&self, _: GuildId, user: UserId, roles: &[RoleId]) -> Result<(), ApiError> {
+        let mut members = self.members.borrow_mut();
+        let member = members.get_mut(&user).ok_or_else(|| not_found("member"))?;
+        member.retain(|role| !roles.contains(role));
+        Ok(())
+    }
+
+    fn log(&self, message: &str) {
+        self.logs.borrow_mut().push(message.to_string());
+    }
+}
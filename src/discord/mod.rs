@@ -0,0 +1,112 @@
+use chrono::{DateTime, FixedOffset};
+use serenity::model::prelude::*;
+
+use std::fmt::Debug;
+use std::time::SystemTime;
+
+#[cfg(test)]
+pub mod fake;
+pub mod serenity_api;
+
+pub use self::serenity_api::SerenityApi;
+
+/// Everything the bot asks of Discord. Commands go through this instead of
+/// calling serenity directly, so that they can be run against a fake guild.
+pub trait DiscordApi {
+    fn say(&self, channel: ChannelId, content: &str) -> Result<MessageId, ApiError>;
+    fn dm(&self, user: UserId, content: &str) -> Result<MessageId, ApiError>;
+    fn react(&self, channel: ChannelId, message: MessageId, emoji: &str) -> Result<(), ApiError>;
+    fn delete_message(&self, channel: ChannelId, message: MessageId) -> Result<(), ApiError>;
+    fn delete_messages(&self, channel: ChannelId, messages: &[MessageId]) -> Result<(), ApiError>;
+
+    /// Up to `limit` messages from the channel, starting from the newest one
+    /// or from just before `before`
+    fn messages(
+        &self,
+        channel: ChannelId,
+        before: Option<MessageId>,
+        limit: u64,
+    ) -> Result<Vec<ChannelMessage>, ApiError>;
+
+    fn member_roles(&self, guild: GuildId, user: UserId) -> Result<Vec<RoleId>, ApiError>;
+    fn add_roles(&self, guild: GuildId, user: UserId, roles: &[RoleId]) -> Result<(), ApiError>;
+    fn remove_roles(&self, guild: GuildId, user: UserId, roles: &[RoleId]) -> Result<(), ApiError>;
+
+    /// Posts to the bot's log channel
+    fn log(&self, message: &str);
+
+    fn reply(&self, msg: &MessageRef, content: &str) -> Result<MessageId, ApiError> {
+        self.say(
+            msg.channel,
+            &format!("{}: {}", msg.author.mention(), content),
+        )
+    }
+}
+
+pub fn logres<T, E>(api: &DiscordApi, result: Result<T, E>)
+where
+    E: Debug,
+{
+    if let Err(e) = result {
+        api.log(&format!("{:#?}", e));
+    }
+}
+
+#[derive(Debug)]
+pub enum ApiError {
+    /// Discord wants us to hold off until then
+    RateLimited(SystemTime),
+    Serenity(::serenity::Error),
+    /// Anything else, mostly from the fake guild in tests
+    Other(String),
+}
+
+/// The bits of a message that commands need to answer it
+#[derive(Clone, Copy, Debug)]
+pub struct MessageRef {
+    pub id: MessageId,
+    pub channel: ChannelId,
+    pub guild: Option<GuildId>,
+    pub author: UserId,
+}
+
+impl<'a> From<&'a Message> for MessageRef {
+    fn from(msg: &'a Message) -> MessageRef {
+        MessageRef {
+            id: msg.id,
+            channel: msg.channel_id,
+            guild: msg.guild_id(),
+            author: msg.author.id,
+        }
+    }
+}
+
+/// A message read back out of a channel's history
+#[derive(Clone, Debug)]
+pub struct ChannelMessage {
+    pub id: MessageId,
+    pub author: UserId,
+    /// The author's `name#1234`, as it was when the message was read
+    pub author_tag: String,
+    pub content: String,
+    pub timestamp: DateTime<FixedOffset>,
+    /// When it was last edited, if it was
+    pub edited: Option<DateTime<FixedOffset>>,
+    pub attachments: Vec<String>,
+    pub pinned: bool,
+}
+
+impl<'a> From<&'a Message> for ChannelMessage {
+    fn from(msg: &'a Message) -> ChannelMessage {
+        ChannelMessage {
+            id: msg.id,
+            author: msg.author.id,
+            author_tag: msg.author.tag(),
+            content: msg.content.clone(),
+            timestamp: msg.timestamp,
+            edited: msg.edited_timestamp,
+            attachments: msg.attachments.iter().map(|a| a.url.clone()).collect(),
+            pinned: msg.pinned,
+        }
+    }
+}
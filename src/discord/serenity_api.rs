@@ -0,0 +1,103 @@
+use serenity::model::prelude::*;
+use serenity::prelude::*;
+use serenity::Error;
+
+use std::str;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::{ApiError, ChannelMessage, DiscordApi};
+
+/// Talks to the real Discord
+pub struct SerenityApi {
+    log_channel: ChannelId,
+}
+
+impl SerenityApi {
+    pub fn new(ctx: &Context) -> SerenityApi {
+        SerenityApi {
+            log_channel: ::logchan(ctx),
+        }
+    }
+}
+
+impl DiscordApi for SerenityApi {
+    fn say(&self, channel: ChannelId, content: &str) -> Result<MessageId, ApiError> {
+        Ok(channel.say(content).map_err(convert)?.id)
+    }
+
+    fn dm(&self, user: UserId, content: &str) -> Result<MessageId, ApiError> {
+        let channel = user.create_dm_channel().map_err(convert)?;
+        Ok(channel.say(content).map_err(convert)?.id)
+    }
+
+    fn react(&self, channel: ChannelId, message: MessageId, emoji: &str) -> Result<(), ApiError> {
+        channel
+            .create_reaction(message, ReactionType::Unicode(emoji.to_string()))
+            .map_err(convert)
+    }
+
+    fn delete_message(&self, channel: ChannelId, message: MessageId) -> Result<(), ApiError> {
+        channel.delete_message(message).map_err(convert)
+    }
+
+    fn delete_messages(&self, channel: ChannelId, messages: &[MessageId]) -> Result<(), ApiError> {
+        channel.delete_messages(messages).map_err(convert)
+    }
+
+    fn messages(
+        &self,
+        channel: ChannelId,
+        before: Option<MessageId>,
+        limit: u64,
+    ) -> Result<Vec<ChannelMessage>, ApiError> {
+        let msgs = match before {
+            Some(before) => channel.messages(|get| get.before(before).limit(limit)),
+            None => channel.messages(|get| get.most_recent().limit(limit)),
+        };
+        Ok(msgs
+            .map_err(convert)?
+            .iter()
+            .map(ChannelMessage::from)
+            .collect())
+    }
+
+    fn member_roles(&self, guild: GuildId, user: UserId) -> Result<Vec<RoleId>, ApiError> {
+        Ok(guild.member(user).map_err(convert)?.roles)
+    }
+
+    fn add_roles(&self, guild: GuildId, user: UserId, roles: &[RoleId]) -> Result<(), ApiError> {
+        let mut member = guild.member(user).map_err(convert)?;
+        member.add_roles(roles).map_err(convert)
+    }
+
+    fn remove_roles(&self, guild: GuildId, user: UserId, roles: &[RoleId]) -> Result<(), ApiError> {
+        let mut member = guild.member(user).map_err(convert)?;
+        member.remove_roles(roles).map_err(convert)
+    }
+
+    fn log(&self, message: &str) {
+        eprintln!("LOG: {}", message);
+        match self.log_channel.say(message) {
+            Ok(_) => {}
+            Err(e) => eprintln!("Log failure: {}", e),
+        }
+    }
+}
+
+fn convert(e: Error) -> ApiError {
+    if let Error::Http(HttpError::UnsuccessfulRequest(ref resp)) = e {
+        if header_int(resp.headers.get_raw("X-RateLimit-Remaining")) == Some(0) {
+            let reset = header_int(resp.headers.get_raw("X-RateLimit-Reset"))
+                .map(|reset| UNIX_EPOCH + Duration::from_secs(reset))
+                .unwrap_or_else(SystemTime::now);
+            return ApiError::RateLimited(reset);
+        }
+    }
+    ApiError::Serenity(e)
+}
+
+fn header_int(data: Option<&[Vec<u8>]>) -> Option<u64> {
+    data.and_then(|parts| parts.get(0))
+        .and_then(|part| str::from_utf8(part).ok())
+        .and_then(|part| part.parse().ok())
+}
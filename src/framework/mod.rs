@@ -3,9 +3,7 @@ use serenity::model::prelude::*;
 use serenity::prelude::*;
 use threadpool::ThreadPool;
 
-use std::mem::replace;
-
-use {bot_gid, bot_uid, grammar};
+use {bot_guilds, bot_prefix, bot_uid, commands, grammar, is_bot_guild};
 
 pub struct BotFramework {}
 
@@ -18,44 +16,64 @@ impl Framework for BotFramework {
 
         let msg_gid = msg.guild_id();
 
-        if msg_gid.is_none() {
-            let _ = msg.reply(
-                "Hey, it's cool that you're trying to PM me, but that's not gonna work sorry~",
-            );
-            return;
+        if let Some(gid) = msg_gid {
+            if !is_bot_guild(&ctx, gid) {
+                return;
+            }
         }
 
-        if msg_gid != Some(bot_gid(&ctx)) {
+        // Answers to feedback get handled along with the feedback itself
+        if msg_gid.is_none() && commands::feedback::parse_reply(&msg.content).is_some() {
             return;
         }
 
-        if msg.mentions.iter().find(|m| m.id == bot_uid) == None {
+        // Everything in a DM is meant for us, so it doesn't need a mention
+        let mentioned = msg.mentions.iter().find(|m| m.id == bot_uid).is_some();
+        let prefixed =
+            bot_prefix(&ctx).and_then(|prefix| expand_prefix(&msg.content, &prefix, bot_uid));
+        if let Some(content) = prefixed {
+            msg.content = content;
+        } else if msg_gid.is_none() && !mentioned {
+            msg.content = format!("<@{}> {}", bot_uid.0, msg.content);
+        } else if !mentioned {
             return;
         }
 
         pool.execute(move || {
             let _ = msg.channel_id.broadcast_typing();
 
-            let clen = msg.content.len();
-            let content = replace(&mut msg.content, String::with_capacity(clen));
-            let first_mention = content.find("<@").unwrap();
-            msg.content.extend(
-                content[first_mention..]
-                    .chars()
-                    .flat_map(|c| c.to_lowercase()),
-            );
+            let first_mention = msg.content.find("<@").unwrap();
+            msg.content = lowercase_command(&msg.content[first_mention..]);
             println!("{}", msg.content);
 
-            let cmdmember = match msg.member() {
-                Some(m) => m,
-                None => return,
+            let (guild, cmdmember) = match msg_gid {
+                Some(gid) => match msg.member() {
+                    Some(m) => (gid, m),
+                    None => return,
+                },
+                None => match dm_member(&ctx, msg.author.id) {
+                    Some(found) => found,
+                    None => {
+                        let _ = msg.reply("Sorry, I only take DMs from members of the server~");
+                        return;
+                    }
+                },
             };
 
             let cmduser = msg.author.id;
             let (bot_mntn, mut cmd) = match grammar::parse_command(cmduser, &msg.content) {
                 Ok(cmd) => cmd,
-                Err(_) => {
-                    let _ = msg.react("\u{1F615}");
+                // Only explain if it was actually meant for us
+                Err(e) => {
+                    if msg.content.starts_with(&format!("<@{}>", bot_uid.0))
+                        || msg.content.starts_with(&format!("<@!{}>", bot_uid.0))
+                    {
+                        let reply =
+                            grammar::suggest::explain(&msg.content, &e, cmduser, &cmdmember);
+                        let _ = msg.reply(&reply);
+                    } else {
+                        let _ = msg.react("\u{1F615}");
+                    }
                     return;
                 }
             };
@@ -64,12 +82,147 @@ impl Framework for BotFramework {
                 return;
             }
 
+            if msg_gid.is_none() && !cmd.is_allowed_in_dms(cmduser) {
+                let _ = msg.reply("That only works in the server, sorry~");
+                return;
+            }
+
             if !cmd.is_authorized(cmduser, &cmdmember) {
                 let _ = msg.react("\u{274C}");
                 return;
             }
 
-            cmd.execute(&ctx, &msg);
+            if msg_gid.is_some() {
+                cmd.execute(&ctx, &msg);
+            } else {
+                cmd.execute_in_dm(&ctx, &msg, guild, &cmdmember);
+            }
         });
     }
 }
+
+/// Whoever sent a DM, as a member of the first of our guilds they're in
+fn dm_member(ctx: &Context, user: UserId) -> Option<(GuildId, Member)> {
+    bot_guilds(ctx)
+        .iter()
+        .filter_map(|&guild| guild.member(user).ok().map(|member| (guild, member)))
+        .next()
+}
+
+/// Lowercases a command so that it parses however it was typed, apart from
+/// anything in double quotes, where the case can matter (like in a purge
+/// pattern). A quote that's never closed doesn't count.
+fn lowercase_command(content: &str) -> String {
+    let mut lowered = String::with_capacity(content.len());
+    let mut rest = content;
+    while let Some(open) = rest.find('"') {
+        let close = match rest[open + 1..].find('"') {
+            Some(close) => open + 1 + close,
+            None => break,
+        };
+        lowered.extend(rest[..open].chars().flat_map(|c| c.to_lowercase()));
+        lowered.push_str(&rest[open..close + 1]);
+        rest = &rest[close + 1..];
+    }
+    lowered.extend(rest.chars().flat_map(|c| c.to_lowercase()));
+    lowered
+}
+
+/// Turns a message that starts with the text prefix into one that starts
+/// with a mention, so that both get parsed the same way. A prefix that ends
+/// in a letter or number has to be a word of its own, so "tb" doesn't catch
+/// "tbh".
+fn expand_prefix(content: &str, prefix: &str, bot_uid: UserId) -> Option<String> {
+    let content = content.trim_left();
+    let rest = match content.get(..prefix.len()) {
+        Some(start) if start.eq_ignore_ascii_case(prefix) => &content[prefix.len()..],
+        _ => return None,
+    };
+    let runs_on = |c: Option<char>| c.map_or(false, char::is_alphanumeric);
+    if runs_on(prefix.chars().last()) && runs_on(rest.chars().next()) {
+        return None;
+    }
+    Some(format!("<@{}> {}", bot_uid.0, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use commands::purge::{Filter, Range};
+    use grammar::ast::Command;
+
+    const BOT: UserId = UserId(7);
+
+    #[test]
+    fn keeps_the_case_of_quoted_text() {
+        assert_eq!(
+            lowercase_command(r#"<@1> Purge, MATCHING "\S+ Nitro" And "A""#),
+            r#"<@1> purge, matching "\S+ Nitro" and "A""#
+        );
+        assert_eq!(lowercase_command(r#"<@1> Say "HI"#), r#"<@1> say "hi"#);
+    }
+
+    #[test]
+    fn parses_a_whole_purge() {
+        let content = lowercase_command(
+            "<@7> Immediately purge all records from this channel, <#50>, \
+             from https://discord.com/channels/1/50/300 to 400, \
+             only from <@2>, matching \"\\S+ NITRO\", including pinned. \
+             I know this action may not be undone and am prepared for this action. \
+             The challenge code is: tcode",
+        );
+        let (channel, first, last, filters, code) =
+            match grammar::parse_command(UserId(5), &content) {
+                Ok((
+                    _,
+                    Command::PurgeChannel(channel, Range::Messages(first, last), filters, code),
+                )) => (channel, first, last, filters, code),
+                other => panic!("{:?}", other),
+            };
+
+        assert_eq!(channel, ChannelId(50));
+        assert_eq!(first.place, Some((GuildId(1), ChannelId(50))));
+        assert_eq!(first.id, MessageId(300));
+        assert_eq!(last.place, None);
+        assert_eq!(last.id, MessageId(400));
+        assert_eq!(code, "tcode");
+        match &filters[..] {
+            [Filter::From(users), Filter::Matching(pattern), Filter::IncludingPinned] => {
+                assert_eq!(users, &[UserId(2)]);
+                assert_eq!(pattern, "\\S+ NITRO");
+            }
+            _ => panic!("{:?}", filters),
+        }
+    }
+
+    #[test]
+    fn ticket_numbers_too_big_dont_panic() {
+        match grammar::parse_command(UserId(5), "<@7> unmask feedback #99999999999") {
+            Ok((_, Command::UnmaskFeedback(number))) => assert_eq!(number, u32::max_value()),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn expands_prefix() {
+        let expanded = expand_prefix("  TB roll 1d6", "tb", BOT);
+        assert_eq!(expanded.as_ref().map(|s| &s[..]), Some("<@7>  roll 1d6"));
+        let expanded = expand_prefix("!roll 1d6", "!", BOT);
+        assert_eq!(expanded.as_ref().map(|s| &s[..]), Some("<@7> roll 1d6"));
+    }
+
+    #[test]
+    fn prefix_has_to_end_a_word() {
+        assert_eq!(expand_prefix("tbh that's cute", "tb", BOT), None);
+        assert_eq!(expand_prefix("tb2", "tb", BOT), None);
+        assert!(expand_prefix("tb, roll 1d6", "tb", BOT).is_some());
+        assert!(expand_prefix("tb", "tb", BOT).is_some());
+        assert!(expand_prefix("tb\nroll 1d6", "tb", BOT).is_some());
+    }
+
+    #[test]
+    fn symbol_prefix_can_run_on() {
+        assert!(expand_prefix("!!roll", "!!", BOT).is_some());
+        assert_eq!(expand_prefix("hi !roll", "!", BOT), None);
+    }
+}
@@ -1,6 +1,8 @@
-use commands::dice::DiceRoll;
+use commands::dice::{Comparison, DiceExpression};
+use commands::purge;
+use state::challenge::{Action, Ping};
+use state::feedback::Status;
 
-use chrono::{DateTime, FixedOffset};
 use serenity::model::prelude::*;
 use serenity::prelude::*;
 
@@ -26,19 +28,32 @@ pub enum Command {
     AliasRole(String, String),
     RemoveAlias(String),
 
-    ChallengeCode,
-    PurgeChannel(
-        ChannelId,
-        DateTime<FixedOffset>,
-        DateTime<FixedOffset>,
-        String,
-    ),
+    ChallengeCode(Action),
+    PurgeChannel(ChannelId, purge::Range, Vec<purge::Filter>, String),
     ExecutePurge(u32),
     CancelPurge,
+    SetChallengeExpiry(u64),
+    SetChallengePing(Ping),
+    ListChallengeCodes,
+    SetTwoPersonRule(bool),
+    ConfirmAction(Action, u32),
 
     ListAllRoles,
     ListAllAliases,
 
+    SetStaffChannel(String, ChannelId),
+    SetStaffRole(String, RoleId),
+    ListSettings,
+    SetEphemeral(ChannelId, Option<u64>),
+
+    SetAnonymousFeedback(bool),
+    UnmaskFeedback(u32),
+    ListFeedback(Option<Status>, Option<String>),
+    SetFeedbackStatus(u32, Status),
+    SetFeedbackCategory(u32, String),
+
+    Help(Option<String>),
+
     ThankYou,
     OmeaWaNoShinderu,
     Meow,
@@ -50,7 +65,9 @@ pub enum Command {
         to: String,
     },
 
-    Dice(DiceRoll),
+    Dice(DiceExpression),
+    DiceOdds(DiceExpression, Option<(Comparison, i32)>),
+    ReplayDice(u32),
 }
 
 fn has_perm(member: &Member, perm: Permissions) -> bool {
@@ -72,7 +89,9 @@ impl Command {
         }
 
         match self {
-            HowManyPosts(target) => *target == cmduser,
+            HowManyPosts(target) => {
+                *target == cmduser || has_perm(member, Permissions::MANAGE_MESSAGES)
+            }
             SetPronouns { target, .. } => {
                 *target == cmduser || has_perm(member, Permissions::MANAGE_ROLES)
             }
@@ -83,18 +102,66 @@ impl Command {
                 *target == cmduser || has_perm(member, Permissions::MANAGE_ROLES)
             }
             ListAllRoles | ListAllAliases => true,
+            ListFeedback(..) | SetFeedbackStatus(..) | SetFeedbackCategory(..) => {
+                has_perm(member, Permissions::MANAGE_MESSAGES)
+            }
+            Help(..) => true,
             ThankYou => true,
             OmeaWaNoShinderu => true,
             Meow => true,
             Convert { .. } => true,
+            Dice(..) | DiceOdds(..) | ReplayDice(..) => true,
             _ => false,
         }
     }
 
+    /// Commands that are fine to run privately, as long as they're about
+    /// whoever sent them
+    pub fn is_allowed_in_dms(&self, cmduser: UserId) -> bool {
+        use self::Command::*;
+        match self {
+            SetPronouns { target, .. } | GiveRoles { target, .. } | TakeRoles { target, .. } => {
+                *target == cmduser
+            }
+            Help(..) => true,
+            ThankYou | OmeaWaNoShinderu | Meow => true,
+            Convert { .. } => true,
+            Dice(..) | DiceOdds(..) | ReplayDice(..) => true,
+            _ => false,
+        }
+    }
+
+    /// Runs a command from a DM against the guild the user is a member of
+    pub fn execute_in_dm(&mut self, ctx: &Context, msg: &Message, guild: GuildId, member: &Member) {
+        use self::Command::*;
+        use commands;
+        use discord::{MessageRef, SerenityApi};
+
+        let api = SerenityApi::new(ctx);
+        let mut msg_ref = MessageRef::from(msg);
+        msg_ref.guild = Some(guild);
+        match self {
+            SetPronouns { .. } => {
+                commands::pronouns::set_pronouns_with(&api, &::state(ctx), &msg_ref, self);
+            }
+            GiveRoles { .. } => {
+                commands::roles::give_roles_with(&api, &::state(ctx), &msg_ref, self);
+            }
+            TakeRoles { .. } => {
+                commands::roles::take_roles_with(&api, &::state(ctx), &msg_ref, self);
+            }
+            Help(..) => commands::help::help_for(ctx, msg, self, member),
+            _ => self.execute(ctx, msg),
+        }
+    }
+
     pub fn execute(&mut self, ctx: &Context, msg: &Message) {
         use self::Command::*;
         use commands;
         match self {
+            HowManyPosts(..) => {
+                commands::posts::how_many_posts(ctx, msg, self);
+            }
             SetPronouns { .. } => {
                 commands::pronouns::set_pronouns(ctx, msg, self);
             }
@@ -122,7 +189,7 @@ impl Command {
             TakeRoles { .. } => {
                 commands::roles::take_roles(ctx, msg, self);
             }
-            ChallengeCode => {
+            ChallengeCode(..) => {
                 commands::purge::issue_code(ctx, msg, self);
             }
             PurgeChannel(..) => {
@@ -132,8 +199,39 @@ impl Command {
                 commands::purge::execute_purge(ctx, msg, self);
             }
             CancelPurge => {
-                commands::purge::cancel_purge(ctx);
+                commands::purge::cancel_purge(ctx, msg);
+            }
+            SetChallengeExpiry(..) | SetChallengePing(..) | SetTwoPersonRule(..) => {
+                commands::purge::set_code_setting(ctx, msg, self);
+            }
+            ConfirmAction(Action::Purge, _) => {
+                commands::purge::confirm_purge(ctx, msg, self);
+            }
+            ListChallengeCodes => {
+                commands::purge::list_codes(ctx, msg, self);
+            }
+            SetStaffChannel(..) | SetStaffRole(..) => {
+                commands::settings::set_staff_setting(ctx, msg, self);
+            }
+            ListSettings => {
+                commands::settings::list_settings(ctx, msg, self);
+            }
+            SetEphemeral(..) => {
+                commands::ephemeral::set_ephemeral(ctx, msg, self);
+            }
+            SetAnonymousFeedback(..) => {
+                commands::feedback::set_anonymous(ctx, msg, self);
+            }
+            UnmaskFeedback(..) => {
+                commands::feedback::unmask(ctx, msg, self);
+            }
+            ListFeedback(..) => {
+                commands::feedback::list(ctx, msg, self);
+            }
+            SetFeedbackStatus(..) | SetFeedbackCategory(..) => {
+                commands::feedback::update(ctx, msg, self);
             }
+            Help(..) => commands::help::help(ctx, msg, self),
             ThankYou => {
                 commands::niceties::thank_you(msg);
             }
@@ -144,6 +242,9 @@ impl Command {
                 commands::niceties::meow(msg);
             }
             Convert { .. } => commands::convert::convert(ctx, msg, self),
+            Dice(..) => commands::dice::roll_dice(ctx, msg, self),
+            DiceOdds(..) => commands::odds::dice_odds(ctx, msg, self),
+            ReplayDice(..) => commands::dice::replay_dice(ctx, msg, self),
             _ => {
                 let _ = msg.reply("I'm sorry, I don't know how to do that yet :<");
             }
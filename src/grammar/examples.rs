@@ -0,0 +1,166 @@
+use serenity::model::id::UserId;
+
+use grammar::ast::Command;
+use grammar::parse_command;
+
+/// Ways of asking for each command, by topic. `@someone`, `@role` and
+/// `#channel` stand in for mentions.
+pub static EXAMPLES: &[(&str, &str)] = &[
+    ("pronouns", "set my pronouns to she/her"),
+    ("pronouns", "set my pronouns to they/them and xe/xem"),
+    ("pronouns", "remove my pronouns"),
+    ("pronouns", "set @someone's pronouns to he/him"),
+    ("pronouns", "rescan the pronoun list from she/her to any"),
+    ("roles", "give me artist and gamer"),
+    ("roles", "remove gamer"),
+    ("roles", "list all roles"),
+    ("roles", "list all role aliases"),
+    ("roles", "give @someone artist"),
+    ("roles", "remove @someone's gamer"),
+    ("roles", "alias role games to gamer"),
+    ("roles", "remove alias games"),
+    ("roles", "rescan the role list from artist to gamer"),
+    ("posts", "how many posts have i made"),
+    ("posts", "how many posts has @someone made"),
+    ("convert", "convert 200 pg/ml estradiol to pmol/l"),
+    ("dice", "roll 2d6 + 3"),
+    ("dice", "roll 4d6k3"),
+    ("dice", "roll 3d6!"),
+    ("dice", "reroll seed 12345"),
+    ("dice", "what are the odds of rolling 2d6 >= 10"),
+    (
+        "purge",
+        "i formally request a challenge code for a destructive action",
+    ),
+    (
+        "purge",
+        "immediately purge all records from this channel, #channel, \
+         after 2018-01-01T00:00:00Z, before 2018-01-02T00:00:00Z. \
+         i know this action may not be undone and am prepared for this action. \
+         the challenge code is: tcode",
+    ),
+    (
+        "purge",
+        "immediately purge all records from this channel, #channel, \
+         in the last hour, only from @someone and @someone. \
+         i know this action may not be undone and am prepared for this action. \
+         the challenge code is: tcode",
+    ),
+    (
+        "purge",
+        "immediately purge all records from this channel, #channel, \
+         from https://discordapp.com/channels/1/2/3 to https://discordapp.com/channels/1/2/4, \
+         matching \"free nitro\", including pinned. \
+         i know this action may not be undone and am prepared for this action. \
+         the challenge code is: tcode",
+    ),
+    (
+        "purge",
+        "definitely do that purge haha rip all 42 of those messages!",
+    ),
+    ("purge", "cancel purge"),
+    ("purge", "list challenge codes"),
+    ("purge", "challenge codes expire after 10 minutes"),
+    ("purge", "tell nobody about challenge codes"),
+    ("purge", "turn on the two person rule"),
+    ("purge", "i confirm purge request #7"),
+    ("settings", "list all settings"),
+    ("settings", "set the mod channel to #channel"),
+    ("settings", "set the front door to #channel"),
+    ("settings", "set the mod call role to @role"),
+    ("settings", "delete messages in #channel after 24 hours"),
+    ("settings", "stop deleting messages in #channel"),
+    ("feedback", "turn on anonymous feedback"),
+    ("feedback", "turn off anonymous feedback"),
+    ("feedback", "unmask feedback #42"),
+    ("feedback", "list open feedback"),
+    ("feedback", "list feedback about events"),
+    ("feedback", "mark feedback #42 as resolved"),
+    ("feedback", "file feedback #42 under events"),
+];
+
+/// Parses an example as though `cmduser` had sent it
+pub fn example_command(phrase: &str, cmduser: UserId) -> Option<Command> {
+    let content = format!(
+        "<@1> {}",
+        phrase
+            .replace("@someone", "<@0>")
+            .replace("@role", "<@&0>")
+            .replace("#channel", "<#0>")
+    );
+    parse_command(cmduser, &content).ok().map(|(_, cmd)| cmd)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The command each example should turn into, in the same order
+    const EXPECTED: &[&str] = &[
+        "SetPronouns",
+        "SetPronouns",
+        "SetPronouns",
+        "SetPronouns",
+        "RescanPronouns",
+        "GiveRoles",
+        "TakeRoles",
+        "ListAllRoles",
+        "ListAllAliases",
+        "GiveRoles",
+        "TakeRoles",
+        "AliasRole",
+        "RemoveAlias",
+        "RescanRoles",
+        "HowManyPosts",
+        "HowManyPosts",
+        "Convert",
+        "Dice",
+        "Dice",
+        "Dice",
+        "ReplayDice",
+        "DiceOdds",
+        "ChallengeCode",
+        "PurgeChannel",
+        "PurgeChannel",
+        "PurgeChannel",
+        "ExecutePurge",
+        "CancelPurge",
+        "ListChallengeCodes",
+        "SetChallengeExpiry",
+        "SetChallengePing",
+        "SetTwoPersonRule",
+        "ConfirmAction",
+        "ListSettings",
+        "SetStaffChannel",
+        "SetStaffChannel",
+        "SetStaffRole",
+        "SetEphemeral",
+        "SetEphemeral",
+        "SetAnonymousFeedback",
+        "SetAnonymousFeedback",
+        "UnmaskFeedback",
+        "ListFeedback",
+        "ListFeedback",
+        "SetFeedbackStatus",
+        "SetFeedbackCategory",
+    ];
+
+    /// Just the name of the variant, from its debug output
+    fn variant(cmd: &Command) -> String {
+        format!("{:?}", cmd)
+            .chars()
+            .take_while(|c| c.is_alphanumeric())
+            .collect()
+    }
+
+    #[test]
+    fn every_example_parses() {
+        assert_eq!(EXAMPLES.len(), EXPECTED.len());
+        for (&(_, phrase), &expected) in EXAMPLES.iter().zip(EXPECTED) {
+            match example_command(phrase, UserId(5)) {
+                Some(cmd) => assert_eq!(variant(&cmd), expected, "{}", phrase),
+                None => panic!("couldn't parse {:?}", phrase),
+            }
+        }
+    }
+}
@@ -1,7 +1,11 @@
 use lalrpop_util;
 use serenity::model::id::UserId;
 
+use commands::dice::DiceExpression;
+
 pub mod ast;
+pub mod examples;
+pub mod suggest;
 
 #[allow(unused_imports)]
 mod parser;
@@ -14,3 +18,10 @@ pub fn parse_command<'a>(
 ) -> Result<(UserId, ast::Command), Error<'a>> {
     parser::parse_Command(cmduser, message)
 }
+
+pub fn parse_dice<'a>(
+    cmduser: UserId,
+    expression: &'a str,
+) -> Result<DiceExpression, Error<'a>> {
+    parser::parse_DiceExpression(cmduser, expression)
+}
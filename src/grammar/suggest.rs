@@ -0,0 +1,167 @@
+use lalrpop_util::ParseError;
+use serenity::model::prelude::*;
+
+use grammar::examples::{example_command, EXAMPLES};
+use grammar::Error;
+
+const MAX_EXPECTED: usize = 8;
+const MAX_SUGGESTIONS: usize = 3;
+
+/// Explains where a command stopped making sense, along with a few commands
+/// the user might have meant. `content` is what was given to the parser.
+pub fn explain(content: &str, error: &Error, cmduser: UserId, member: &Member) -> String {
+    let (location, expected) = match *error {
+        ParseError::InvalidToken { location } => (location, &[][..]),
+        ParseError::UnrecognizedToken {
+            token: Some((start, _, _)),
+            ref expected,
+        } => (start, &expected[..]),
+        ParseError::UnrecognizedToken {
+            token: None,
+            ref expected,
+        } => (content.len(), &expected[..]),
+        ParseError::ExtraToken {
+            token: (start, _, _),
+        } => (start, &[][..]),
+        ParseError::User { .. } => (content.len(), &[][..]),
+    };
+
+    let understood = understood(&content[..location]);
+    let found = content[location..].split_whitespace().next();
+
+    let mut reply = match (understood.is_empty(), found) {
+        (true, Some(found)) => format!("Sorry, I didn't recognise `{}`.", found),
+        (true, None) => "Sorry, I'm not sure what you want me to do.".to_string(),
+        (false, Some(found)) => format!(
+            "I understood `{}` but didn't recognise `{}` after it.",
+            understood, found
+        ),
+        (false, None) => format!(
+            "I understood `{}` but then you stopped before I knew what you wanted.",
+            understood
+        ),
+    };
+
+    let mut wanted = Vec::new();
+    for token in expected {
+        let token = describe_token(token);
+        if !wanted.contains(&token) {
+            wanted.push(token);
+        }
+    }
+    if !wanted.is_empty() && wanted.len() <= MAX_EXPECTED {
+        reply.push_str(" I was expecting ");
+        reply.push_str(&join_or(&wanted));
+        reply.push('.');
+    }
+
+    let suggestions = suggestions(&understood, cmduser, member);
+    if suggestions.is_empty() {
+        reply.push_str("\nAsk me for `help` to see what I can do~");
+    } else {
+        reply.push_str("\nMaybe you meant something like:");
+        for phrase in suggestions {
+            reply.push_str(&format!("\n`{}`", phrase));
+        }
+    }
+
+    reply
+}
+
+/// What came before the error, without the mention at the start
+fn understood(before: &str) -> String {
+    let start = before.find('>').map(|i| i + 1).unwrap_or(0);
+    before[start..]
+        .trim_left_matches(|c: char| c.is_whitespace() || ",!?.;".contains(c))
+        .trim_right()
+        .to_string()
+}
+
+/// The examples that start the same way as the message did, or as close as
+/// any of them get, leaving out ones the user isn't allowed to run
+fn suggestions(understood: &str, cmduser: UserId, member: &Member) -> Vec<&'static str> {
+    let words = understood.split_whitespace().collect::<Vec<_>>();
+
+    let mut scored = EXAMPLES
+        .iter()
+        .map(|&(_, phrase)| {
+            let matching = phrase
+                .split_whitespace()
+                .zip(&words)
+                .take_while(|&(want, &got)| same_word(want, got))
+                .count();
+            (matching, phrase)
+        })
+        .filter(|&(matching, _)| matching > 0)
+        .filter(|&(_, phrase)| {
+            example_command(phrase, cmduser)
+                .map(|cmd| cmd.is_authorized(cmduser, member))
+                .unwrap_or(false)
+        })
+        .collect::<Vec<_>>();
+
+    let best = scored.iter().map(|&(matching, _)| matching).max();
+    scored.retain(|&(matching, _)| Some(matching) == best);
+    scored
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, phrase)| phrase)
+        .collect()
+}
+
+fn same_word(example: &str, word: &str) -> bool {
+    match example {
+        "@someone" | "@someone's" => word.starts_with("<@"),
+        "#channel" | "#channel," => word.starts_with("<#"),
+        _ => example == word,
+    }
+}
+
+/// Turns one of lalrpop's expected tokens into something readable. Literal
+/// words come quoted, and the rest are the regexes from the grammar.
+fn describe_token(token: &str) -> String {
+    if token.starts_with('"') {
+        return format!("`{}`", token.trim_matches('"'));
+    }
+
+    let description = if token.contains("<@&") {
+        "a role mention"
+    } else if token.contains("<@") {
+        "a mention"
+    } else if token.contains("<#") {
+        "a channel mention"
+    } else if token.contains("discord(app)") {
+        "a message link"
+    } else if token.contains("[^\"]") {
+        "a pattern in quotes like `\"free nitro\"`"
+    } else if token.contains("#[0-9]") {
+        "a feedback number like `#42`"
+    } else if token.contains("-(0[1-9]") {
+        "a timestamp like `2018-01-01T00:00:00Z`"
+    } else if token.contains("d([0-9]+)") {
+        "dice like `2d6`"
+    } else if token.contains("(k|kh") {
+        "a keep or drop like `k3`"
+    } else if token.contains("r[0-9]+") {
+        "a reroll like `r1`"
+    } else if token.contains("(<|<=") {
+        "a target like `>=5`"
+    } else if token.contains("\\.[0-9]+") {
+        "a decimal number"
+    } else if token.contains("[0-9]+") {
+        "a number"
+    } else if token.contains("[A-Za-z]") {
+        "a word"
+    } else {
+        "something else"
+    };
+    description.to_string()
+}
+
+fn join_or(items: &[String]) -> String {
+    match items.split_last() {
+        Some((last, rest)) if !rest.is_empty() => format!("{} or {}", rest.join(", "), last),
+        Some((last, _)) => last.clone(),
+        None => String::new(),
+    }
+}
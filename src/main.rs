@@ -7,6 +7,9 @@ extern crate chrono;
 extern crate dotenv;
 extern crate lalrpop_util;
 extern crate rand;
+extern crate regex;
+#[cfg(feature = "sqlite")]
+extern crate rusqlite;
 extern crate serde_json as json;
 extern crate serenity;
 extern crate threadpool;
@@ -19,18 +22,21 @@ use serenity::model::prelude::*;
 use serenity::prelude::*;
 
 use std::env;
-use std::fmt::Write;
-use std::sync::mpsc::{channel, Sender};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Once, ONCE_INIT};
+use std::time::{Duration, Instant};
 
+pub mod archive;
 pub mod commands;
+pub mod discord;
 pub mod framework;
 pub mod grammar;
+pub mod rng;
 pub mod state;
 
-struct BotGuildId;
-impl typemap::Key for BotGuildId {
-    type Value = GuildId;
+/// The guilds the bot serves, in the order they were configured
+struct BotGuilds;
+impl typemap::Key for BotGuilds {
+    type Value = Arc<Vec<GuildId>>;
 }
 struct BotUserId;
 impl typemap::Key for BotUserId {
@@ -40,9 +46,16 @@ struct BotLogChannel;
 impl typemap::Key for BotLogChannel {
     type Value = ChannelId;
 }
+/// Text that works the same as mentioning the bot, like `t!`
+struct BotPrefix;
+impl typemap::Key for BotPrefix {
+    type Value = Option<String>;
+}
 impl typemap::Key for state::State {
     type Value = Arc<state::State>;
 }
+/// Where staff get told about things, set up per guild with admin commands
+#[derive(Clone, Serialize, Deserialize)]
 pub struct StaffAlertData {
     admin_channel: ChannelId,
     mod_channel: ChannelId,
@@ -52,9 +65,6 @@ pub struct StaffAlertData {
     anon_feedback: ChannelId,
     feedback_log: ChannelId,
 }
-impl typemap::Key for StaffAlertData {
-    type Value = Arc<StaffAlertData>;
-}
 
 fn env_token<F, R>(token: &str, f: F) -> R
 where
@@ -67,43 +77,113 @@ where
         .expect(token)
 }
 
+/// Default settings for a guild come from `NAME_<guild id>`. The first guild
+/// can also use plain `NAME`, from back when there was only ever one.
+fn guild_token<F, R>(token: &str, guild: GuildId, first: bool, f: F) -> R
+where
+    F: Fn(u64) -> R,
+{
+    let specific = format!("{}_{}", token, guild.0);
+    if first && env::var(&specific).is_err() {
+        env_token(token, f)
+    } else {
+        env_token(&specific, f)
+    }
+}
+
 fn main() {
     let _ = dotenv::dotenv();
+
+    if env::args().nth(1).as_ref().map(|s| &s[..]) == Some("migrate-state-to-sqlite") {
+        migrate_state_to_sqlite();
+        return;
+    }
+
     let token = env::var("DISCORD_TOKEN").expect("Please specify DISCORD_TOKEN");
-    let guildid = env_token("BOT_GUILD_ID", GuildId);
     let logchan = env_token("BOT_LOG_CHANNEL", ChannelId);
-    let admin_channel = env_token("ADMIN_CHANNEL", ChannelId);
-    let mod_channel = env_token("MOD_CHANNEL", ChannelId);
-    let front_door = env_token("FRONT_DOOR", ChannelId);
-    let mod_call = env_token("MOD_CALL", RoleId);
-    let the_void = env_token("THE_VOID", ChannelId);
-    let anon_feedback = env_token("ANON_FEEDBACK", ChannelId);
-    let feedback_log = env_token("FEEDBACK_LOG", ChannelId);
-
-    let _ = DELETE_QUEUE.lock().unwrap().send(Err(the_void));
-
-    let staff_alert = Arc::new(StaffAlertData {
-        admin_channel,
-        mod_channel,
-        front_door,
-        mod_call,
-        the_void,
-        anon_feedback,
-        feedback_log,
-    });
-
-    let state = Arc::new(state::State::load());
+    let prefix = env::var("BOT_PREFIX")
+        .ok()
+        .map(|prefix| prefix.trim().to_string())
+        .filter(|prefix| !prefix.is_empty());
+    let guild_ids = env::var("BOT_GUILD_ID").expect("Please specify BOT_GUILD_ID");
+
+    let state = match state::storage::from_env().and_then(state::State::load) {
+        Ok(state) => Arc::new(state),
+        Err(e) => {
+            eprintln!(
+                "Couldn't load the state: {}\n\
+                 Refusing to start so that nothing gets overwritten. \
+                 Fix or move the saved state and try again.",
+                e
+            );
+            std::process::exit(1);
+        }
+    };
+
+    let mut guilds = Vec::new();
+    for (i, id) in guild_ids.split(',').enumerate() {
+        let guild = id.trim().parse().map(GuildId).expect("BOT_GUILD_ID");
+        guilds.push(guild);
+
+        // The env vars are only needed until the settings have been saved once
+        let guild_state = state.guild(guild);
+        if guild_state.staff_alert().is_some() {
+            continue;
+        }
+        let first = i == 0;
+        guild_state.set_staff_alert(StaffAlertData {
+            admin_channel: guild_token("ADMIN_CHANNEL", guild, first, ChannelId),
+            mod_channel: guild_token("MOD_CHANNEL", guild, first, ChannelId),
+            front_door: guild_token("FRONT_DOOR", guild, first, ChannelId),
+            mod_call: guild_token("MOD_CALL", guild, first, RoleId),
+            the_void: guild_token("THE_VOID", guild, first, ChannelId),
+            anon_feedback: guild_token("ANON_FEEDBACK", guild, first, ChannelId),
+            feedback_log: guild_token("FEEDBACK_LOG", guild, first, ChannelId),
+        });
+        state
+            .save_staff_alert(guild)
+            .expect("Couldn't save the staff settings");
+    }
 
     let mut client = Client::new(&token, Handler).unwrap();
 
-    client.data.lock().insert::<BotGuildId>(guildid);
+    client.data.lock().insert::<BotGuilds>(Arc::new(guilds));
     client.data.lock().insert::<BotLogChannel>(logchan);
-    client.data.lock().insert::<state::State>(state);
-    client.data.lock().insert::<StaffAlertData>(staff_alert);
+    client.data.lock().insert::<BotPrefix>(prefix);
+    client.data.lock().insert::<state::State>(state.clone());
 
     client.with_framework(framework::BotFramework {});
 
-    client.start().unwrap();
+    let result = client.start();
+    // Anything counted since the last flush would be lost otherwise
+    if let Err(e) = state.save_post_counts() {
+        eprintln!("Couldn't save the post counts: {}", e);
+    }
+    result.unwrap();
+}
+
+/// Copies state.json into a fresh SQLite database, for switching over to
+/// `STATE_BACKEND=sqlite`. This is run by hand while the bot is stopped.
+#[cfg(feature = "sqlite")]
+fn migrate_state_to_sqlite() {
+    use state::storage::json_file::{JsonFile, STATE_FILE};
+    use state::storage::sqlite::{self, Sqlite};
+
+    let path = sqlite::db_path();
+    let result = Sqlite::open(&path).and_then(|db| sqlite::import(&JsonFile::new(STATE_FILE), &db));
+    match result {
+        Ok(rows) => println!("Copied {} into {} ({} entries)", STATE_FILE, path, rows),
+        Err(e) => {
+            eprintln!("Couldn't migrate {} to {}: {}", STATE_FILE, path, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn migrate_state_to_sqlite() {
+    eprintln!("This build doesn't have SQLite support, rebuild with `--features sqlite`");
+    std::process::exit(1);
 }
 
 pub fn state(ctx: &Context) -> Arc<state::State> {
@@ -114,8 +194,16 @@ pub fn logchan(ctx: &Context) -> ChannelId {
     ctx.data.lock().get::<BotLogChannel>().cloned().unwrap()
 }
 
-pub fn bot_gid(ctx: &Context) -> GuildId {
-    ctx.data.lock().get::<BotGuildId>().cloned().unwrap()
+pub fn bot_prefix(ctx: &Context) -> Option<String> {
+    ctx.data.lock().get::<BotPrefix>().cloned().unwrap()
+}
+
+pub fn bot_guilds(ctx: &Context) -> Arc<Vec<GuildId>> {
+    ctx.data.lock().get::<BotGuilds>().cloned().unwrap()
+}
+
+pub fn is_bot_guild(ctx: &Context, guild: GuildId) -> bool {
+    ctx.data.lock().get::<BotGuilds>().unwrap().contains(&guild)
 }
 
 pub fn bot_uid(ctx: &Context) -> UserId {
@@ -146,37 +234,149 @@ where
     }
 }
 
-pub fn staff_alert(ctx: &Context) -> Arc<StaffAlertData> {
-    ctx.data.lock().get::<StaffAlertData>().cloned().unwrap()
+/// The staff channels for a guild, or `None` if it isn't one of ours
+pub fn staff_alert(ctx: &Context, guild: GuildId) -> Option<Arc<StaffAlertData>> {
+    if !is_bot_guild(ctx, guild) {
+        return None;
+    }
+    state(ctx).guild(guild).staff_alert()
 }
 
-lazy_static! {
-    static ref DELETE_QUEUE: Arc<Mutex<Sender<Result<MessageId, ChannelId>>>> = {
-        let (tx, rx) = channel::<Result<MessageId, ChannelId>>();
-        std::thread::spawn(move || {
-            let channel = rx.recv().unwrap().unwrap_err();
-            let mut buf = vec![];
-            while let Ok(Ok(first)) = rx.recv() {
-                buf.push(first);
-                std::thread::sleep(std::time::Duration::from_secs(1));
-                while let Ok(Ok(next)) = rx.try_recv() {
-                    buf.push(next);
-                }
-                std::thread::sleep(std::time::Duration::from_secs(5));
-                let _ = channel.delete_messages(&buf);
-                buf.clear();
+/// Deletes messages from the auto-deleting channels once they're due,
+/// including any that were still waiting when the bot last stopped. Post
+/// counts get saved from here too, so that they don't wait on the next
+/// message to come along.
+fn delete_queue(context: Context) {
+    let mut last_flush = Instant::now();
+    loop {
+        let now = chrono::Utc::now().timestamp();
+        for &guild in bot_guilds(&context).iter() {
+            commands::ephemeral::delete_due(
+                &discord::SerenityApi::new(&context),
+                &state(&context),
+                guild,
+                now,
+            );
+        }
+
+        if last_flush.elapsed() >= Duration::from_secs(state::posts::FLUSH_INTERVAL) {
+            if let Err(e) = state(&context).save_post_counts() {
+                log(&context, &format!("Couldn't save the post counts: {}", e));
             }
-        });
+            last_flush = Instant::now();
+        }
+        std::thread::sleep(Duration::from_secs(1));
+    }
+}
 
-        Arc::new(Mutex::new(tx))
-    };
+/// Carries on with any purge that was in progress when the bot last stopped
+fn resume_purges(context: Context) {
+    for &guild in bot_guilds(&context).iter() {
+        if let Some(staff_alert) = staff_alert(&context, guild) {
+            commands::purge::resume_purge(
+                &discord::SerenityApi::new(&context),
+                &state(&context),
+                &staff_alert,
+                guild,
+            );
+        }
+    }
+}
+
+/// Someone following up on their feedback in a DM
+fn feedback_reply(context: &Context, msg: &Message) {
+    let content = msg.content_safe();
+    if let Some((number, text)) = commands::feedback::parse_reply(&content) {
+        commands::feedback::author_reply(
+            &discord::SerenityApi::new(context),
+            &state(context),
+            &bot_guilds(context),
+            &msg.into(),
+            &msg.author.tag(),
+            number,
+            text,
+        );
+    }
+}
+
+/// Checks every hour whether any guild is due its weekly feedback digest
+fn feedback_digests(context: Context) {
+    loop {
+        std::thread::sleep(std::time::Duration::from_secs(60 * 60));
+        let now = chrono::Utc::now().timestamp();
+        for &guild in bot_guilds(&context).iter() {
+            if let Some(staff_alert) = staff_alert(&context, guild) {
+                commands::feedback::digest(
+                    &discord::SerenityApi::new(&context),
+                    &state(&context),
+                    &staff_alert,
+                    guild,
+                    now,
+                );
+            }
+        }
+    }
 }
 
 struct Handler;
 impl EventHandler for Handler {
+    fn ready(&self, context: Context, _: Ready) {
+        // This fires again on every reconnect
+        static BACKGROUND: Once = ONCE_INIT;
+        BACKGROUND.call_once(move || {
+            let digests = context.clone();
+            std::thread::spawn(move || feedback_digests(digests));
+            let purges = context.clone();
+            std::thread::spawn(move || resume_purges(purges));
+            std::thread::spawn(move || delete_queue(context));
+        });
+    }
+
     fn message(&self, context: Context, msg: Message) {
-        let staff_alert = staff_alert(&context);
-        if msg.channel_id != staff_alert.the_void && msg.channel_id != staff_alert.anon_feedback {
+        let guild_id = match msg.guild_id() {
+            Some(guild_id) => guild_id,
+            None => {
+                if !msg.author.bot {
+                    feedback_reply(&context, &msg);
+                }
+                return;
+            }
+        };
+        let staff_alert = match staff_alert(&context, guild_id) {
+            Some(staff_alert) => staff_alert,
+            None => return,
+        };
+
+        // Nobody gets counted for what they say in the void or in anonymous
+        // feedback, that would kind of defeat the point
+        if !msg.author.bot
+            && msg.channel_id != staff_alert.the_void
+            && msg.channel_id != staff_alert.anon_feedback
+        {
+            state(&context)
+                .guild(guild_id)
+                .post_counts
+                .increment(msg.author.id, msg.channel_id);
+        }
+
+        if msg.channel_id == staff_alert.feedback_log && !msg.author.bot {
+            let content = msg.content_safe();
+            if let Some((number, text)) = commands::feedback::parse_reply(&content) {
+                commands::feedback::staff_reply(
+                    &discord::SerenityApi::new(&context),
+                    &state(&context),
+                    guild_id,
+                    &(&msg).into(),
+                    number,
+                    text,
+                );
+            }
+            return;
+        }
+
+        let ttl =
+            commands::ephemeral::ttl(&state(&context), &staff_alert, guild_id, msg.channel_id);
+        if ttl.is_none() && msg.channel_id != staff_alert.anon_feedback {
             return;
         }
 
@@ -203,53 +403,46 @@ impl EventHandler for Handler {
         }
 
         if msg.channel_id == staff_alert.anon_feedback {
-            let data = format!(
-                "{id} (`{name}#{disc:04}`) provided some anonymous feedback - {time}\n\
-                 \"{content}\"",
-                id = msg.author.id.mention(),
-                name = msg.author.name,
-                disc = msg.author.discriminator,
-                content = msg.content_safe().trim(),
-                time = msg.timestamp.to_rfc2822(),
+            let mut feedback = discord::ChannelMessage::from(&msg);
+            feedback.content = msg.content_safe();
+            commands::feedback::submit(
+                &discord::SerenityApi::new(&context),
+                &state(&context),
+                &staff_alert,
+                guild_id,
+                &feedback,
+                &msg.author.tag(),
             );
 
-            let _ = staff_alert.feedback_log.say(data);
-
             std::thread::spawn(move || {
                 std::thread::sleep(std::time::Duration::from_millis(100));
                 let _ = msg.delete();
             });
-        } else {
-            let _ = DELETE_QUEUE.lock().unwrap().send(Ok(msg.id));
+        } else if let Some(ttl) = ttl {
+            commands::ephemeral::schedule(
+                &state(&context),
+                guild_id,
+                msg.channel_id,
+                msg.id,
+                msg.timestamp.timestamp(),
+                ttl,
+            );
         }
     }
 
     fn guild_member_addition(&self, context: Context, guild: GuildId, member: Member) {
-        if guild != bot_gid(&context) {
-            return;
-        }
-
-        let staff_alert = staff_alert(&context);
-        let state = state(&context);
+        let staff_alert = match staff_alert(&context, guild) {
+            Some(staff_alert) => staff_alert,
+            None => return,
+        };
 
-        let mut message = format!(
-            "Hey {modcall}, {user} just joined! Check the {frontdoor}~",
-            modcall = staff_alert.mod_call.mention(),
-            frontdoor = staff_alert.front_door.mention(),
-            user = member.mention(),
+        commands::members::member_joined(
+            &discord::SerenityApi::new(&context),
+            &state(&context),
+            &staff_alert,
+            guild,
+            member.user.read().id,
         );
-
-        let times = state.leave_counts.get(member.user.read().id);
-        if times > 0 {
-            let _ = write!(
-                &mut message,
-                " (they're back for the {}{} time)",
-                times,
-                cardinality(times)
-            );
-        }
-
-        let _ = staff_alert.mod_channel.say(message);
     }
 
     fn guild_member_removal(
@@ -259,34 +452,16 @@ impl EventHandler for Handler {
         user: User,
         _: Option<Member>,
     ) {
-        if guild != bot_gid(&context) {
+        if !is_bot_guild(&context, guild) {
             return;
         }
 
-        let state = state(&context);
-        let times = state.leave_counts.increment(user.id);
-        let _ = state.save();
-
-        log(
-            &context,
-            &format!(
-                "{} ({}#{}) left the server ({}{} time)",
-                user.mention(),
-                user.name,
-                user.discriminator,
-                times,
-                cardinality(times)
-            ),
+        commands::members::member_left(
+            &discord::SerenityApi::new(&context),
+            &state(&context),
+            guild,
+            user.id,
+            &user.tag(),
         );
     }
 }
-
-fn cardinality(i: u32) -> &'static str {
-    match ((i / 10) % 10, i % 10) {
-        (1, _) => "th",
-        (_, 1) => "st",
-        (_, 2) => "nd",
-        (_, 3) => "rd",
-        (_, _) => "th",
-    }
-}
@@ -0,0 +1,36 @@
+use rand::{thread_rng, Isaac64Rng, Rng, SeedableRng, ThreadRng};
+
+/// Picks a new seed. Anything random the bot does should start from one of
+/// these, so that it can be logged and played back later.
+pub fn new_seed() -> u32 {
+    thread_rng().next_u32()
+}
+
+pub fn seeded(seed: u32) -> Isaac64Rng {
+    Isaac64Rng::from_seed(&[seed as u64])
+}
+
+/// Random bits for secrets, like salts. These deliberately don't come from a
+/// seed, since a seed is small enough to guess.
+pub fn secret() -> u64 {
+    thread_rng().next_u64()
+}
+
+/// A generator for longer secrets, like challenge codes, for the same reason
+pub fn secret_rng() -> ThreadRng {
+    thread_rng()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeds_play_back() {
+        let first: Vec<u32> = seeded(7).gen_iter().take(8).collect();
+        let again: Vec<u32> = seeded(7).gen_iter().take(8).collect();
+        let other: Vec<u32> = seeded(8).gen_iter().take(8).collect();
+        assert_eq!(first, again);
+        assert_ne!(first, other);
+    }
+}
@@ -1,40 +1,396 @@
+use std::fmt;
+use std::mem;
 use std::sync::RwLock;
 
-use rand::{thread_rng, Rng};
-use serenity::model::id::{ChannelId, MessageId};
+use rand::Rng;
+use serenity::model::id::{ChannelId, MessageId, RoleId, UserId};
+
+/// How long a code lasts unless the guild has changed it, in seconds
+pub const DEFAULT_EXPIRY: u64 = 5 * 60;
+/// How many entries the log keeps before dropping the oldest
+const MAX_LOG: usize = 200;
 
 #[derive(Default, Serialize, Deserialize)]
 pub struct Code {
     #[serde(skip)]
-    code: RwLock<Option<String>>,
+    code: RwLock<Option<Issued>>,
+    /// Saved so that a restart in the middle of a purge can pick it back up
+    #[serde(default)]
+    purge_data: RwLock<Option<Purge>>,
+    /// How long codes last, in seconds, if it isn't the default
+    #[serde(default)]
+    expiry: RwLock<Option<u64>>,
+    #[serde(default)]
+    ping: RwLock<Ping>,
+    #[serde(default)]
+    log: RwLock<Vec<LogEntry>>,
+    /// Whether destructive actions need a second admin to confirm them
+    #[serde(default)]
+    two_person_rule: RwLock<bool>,
+    /// The number of the last purge request
+    #[serde(default)]
+    requests: RwLock<u32>,
+    /// An admin who asked to turn the two person rule off, waiting on a
+    /// second, and when that runs out like a code would
     #[serde(skip)]
-    purge_data: RwLock<Option<(ChannelId, Vec<MessageId>)>>,
+    rule_off_request: RwLock<Option<(UserId, i64)>>,
+}
+
+/// A code that's been handed out and not used yet
+struct Issued {
+    code: String,
+    admin: UserId,
+    action: Action,
+    expires: i64,
+}
+
+/// What a code can be used for
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Purge,
+}
+
+/// Who gets pinged when a code is issued
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Ping {
+    Everyone,
+    Role(RoleId),
+    Nobody,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct LogEntry {
+    pub event: Event,
+    /// Who the code was issued to
+    pub admin: UserId,
+    pub action: Action,
+    /// Who used or cancelled it. Nobody does anything to expire a code.
+    pub by: Option<UserId>,
+    pub at: i64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    Issued,
+    Used,
+    Expired,
+    Cancelled,
+    /// Not about a code as such: `admin` turned the two person rule on
+    TwoPersonRuleOn,
+    /// `admin` asked to turn the two person rule off and `by` agreed
+    TwoPersonRuleOff,
+}
+
+/// Why a code didn't work. Only an expired code is gone afterwards, so that
+/// someone else trying it doesn't use it up for the admin it's for.
+#[derive(Debug, PartialEq)]
+pub enum Rejected {
+    NoCode,
+    Expired,
+    Wrong,
+    NotYours,
+    OtherAction(Action),
+}
+
+/// How asking to turn the two person rule on or off went
+#[derive(Debug, PartialEq)]
+pub enum RuleChange {
+    /// Turning it off needs a second admin
+    Requested,
+    /// The same admin asked twice
+    AlreadyRequested,
+    Changed,
+    /// It was already that way
+    Unchanged,
+}
+
+/// A purge, from gathering its messages until the last of them is gone
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Purge {
+    /// Counts up from 1, so that a second admin can say which one they mean
+    #[serde(default)]
+    pub number: u32,
+    pub channel: ChannelId,
+    /// The admin who asked for it
+    pub requested_by: UserId,
+    pub stage: Stage,
+    /// Everything that's going, in the order it gets deleted
+    pub messages: Vec<MessageId>,
+    /// How many of `messages` are already gone
+    #[serde(default)]
+    pub deleted: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Stage {
+    Gathering,
+    /// Waiting for someone to confirm how many messages there are
+    Confirming,
+    /// Waiting for a second admin, under the two person rule
+    Seconding,
+    Deleting,
 }
 
 impl Code {
-    pub fn issue(&self) -> String {
+    /// Hands out a new code for `admin` to use for `action`, replacing
+    /// whatever code there was before
+    pub fn issue<R: Rng>(&self, rng: &mut R, admin: UserId, action: Action, now: i64) -> String {
         let code: String = Some('t')
             .into_iter()
             .chain(
-                thread_rng()
-                    .gen_ascii_chars()
+                rng.gen_ascii_chars()
                     .take(24)
                     .flat_map(|c| c.to_lowercase()),
             )
             .collect();
-        *self.code.write().unwrap() = Some(code.clone());
+        let issued = Issued {
+            code: code.clone(),
+            admin,
+            action,
+            expires: now + self.expiry() as i64,
+        };
+        let old = mem::replace(&mut *self.code.write().unwrap(), Some(issued));
+        if let Some(old) = old {
+            self.end(old, admin, now);
+        }
+        self.record(LogEntry {
+            event: Event::Issued,
+            admin,
+            action,
+            by: Some(admin),
+            at: now,
+        });
         code
     }
 
-    pub fn take(&self) -> Option<String> {
-        self.code.write().unwrap().take()
+    /// Uses up the code, which only works for the admin it was issued to,
+    /// for the action it was issued for, before it expires
+    pub fn redeem(&self, code: &str, by: UserId, action: Action, now: i64) -> Result<(), Rejected> {
+        let mut slot = self.code.write().unwrap();
+        let checked = match *slot {
+            None => Err(Rejected::NoCode),
+            Some(ref issued) => {
+                if now >= issued.expires {
+                    Err(Rejected::Expired)
+                } else if code.trim() != issued.code.trim() {
+                    Err(Rejected::Wrong)
+                } else if by != issued.admin {
+                    Err(Rejected::NotYours)
+                } else if action != issued.action {
+                    Err(Rejected::OtherAction(issued.action))
+                } else {
+                    Ok(())
+                }
+            }
+        };
+
+        match checked {
+            Ok(()) => {
+                let issued = slot.take().unwrap();
+                self.record(LogEntry {
+                    event: Event::Used,
+                    admin: issued.admin,
+                    action,
+                    by: Some(by),
+                    at: now,
+                });
+            }
+            Err(Rejected::Expired) => self.end(slot.take().unwrap(), by, now),
+            Err(_) => (),
+        }
+        checked
+    }
+
+    /// Logs the code as expired if it has by `now`, rather than waiting for
+    /// someone to try it. Returns whether it had.
+    pub fn expire(&self, now: i64) -> bool {
+        let mut slot = self.code.write().unwrap();
+        let expired = match *slot {
+            Some(ref issued) => now >= issued.expires,
+            None => false,
+        };
+        if expired {
+            let issued = slot.take().unwrap();
+            self.record(LogEntry {
+                event: Event::Expired,
+                admin: issued.admin,
+                action: issued.action,
+                by: None,
+                at: issued.expires,
+            });
+        }
+        expired
+    }
+
+    pub fn cancel(&self, by: UserId, now: i64) {
+        if let Some(issued) = self.code.write().unwrap().take() {
+            self.end(issued, by, now);
+        }
+    }
+
+    /// Logs a code going unused, which is down to `by` unless it had
+    /// already expired
+    fn end(&self, issued: Issued, by: UserId, now: i64) {
+        let (event, by, at) = if now >= issued.expires {
+            (Event::Expired, None, issued.expires)
+        } else {
+            (Event::Cancelled, Some(by), now)
+        };
+        self.record(LogEntry {
+            event,
+            admin: issued.admin,
+            action: issued.action,
+            by,
+            at,
+        });
+    }
+
+    fn record(&self, entry: LogEntry) {
+        let mut log = self.log.write().unwrap();
+        log.push(entry);
+        if log.len() > MAX_LOG {
+            log.remove(0);
+        }
+    }
+
+    /// Everything that's happened to codes, oldest first
+    pub fn log(&self) -> Vec<LogEntry> {
+        self.log.read().unwrap().clone()
+    }
+
+    /// How long codes last, in seconds
+    pub fn expiry(&self) -> u64 {
+        self.expiry.read().unwrap().unwrap_or(DEFAULT_EXPIRY)
     }
 
-    pub fn set_purge(&self, purge: Option<(ChannelId, Vec<MessageId>)>) {
+    pub fn set_expiry(&self, expiry: u64) {
+        *self.expiry.write().unwrap() = Some(expiry);
+    }
+
+    pub fn ping(&self) -> Ping {
+        *self.ping.read().unwrap()
+    }
+
+    pub fn set_ping(&self, ping: Ping) {
+        *self.ping.write().unwrap() = ping;
+    }
+
+    pub fn two_person_rule(&self) -> bool {
+        *self.two_person_rule.read().unwrap()
+    }
+
+    /// Turns the two person rule on or off for `by`. Turning it off takes
+    /// two different admins asking, or one admin could turn it off and purge
+    /// on their own. Asking to turn it off lasts as long as a code does.
+    pub fn set_two_person_rule(&self, on: bool, by: UserId, now: i64) -> RuleChange {
+        let mut rule = self.two_person_rule.write().unwrap();
+        let mut request = self.rule_off_request.write().unwrap();
+        if *rule == on {
+            return RuleChange::Unchanged;
+        }
+
+        let admin = if on {
+            by
+        } else {
+            match *request {
+                Some((requested_by, expires)) if now < expires => {
+                    if requested_by == by {
+                        return RuleChange::AlreadyRequested;
+                    }
+                    requested_by
+                }
+                _ => {
+                    *request = Some((by, now + self.expiry() as i64));
+                    return RuleChange::Requested;
+                }
+            }
+        };
+        *rule = on;
+        *request = None;
+        self.record(LogEntry {
+            event: if on {
+                Event::TwoPersonRuleOn
+            } else {
+                Event::TwoPersonRuleOff
+            },
+            admin,
+            action: Action::Purge,
+            by: Some(by),
+            at: now,
+        });
+        RuleChange::Changed
+    }
+
+    pub fn next_request(&self) -> u32 {
+        let mut requests = self.requests.write().unwrap();
+        *requests += 1;
+        *requests
+    }
+
+    pub fn purge(&self) -> Option<Purge> {
+        self.purge_data.read().unwrap().clone()
+    }
+
+    pub fn set_purge(&self, purge: Option<Purge>) {
         *self.purge_data.write().unwrap() = purge
     }
 
-    pub fn take_purge(&self) -> Option<(ChannelId, Vec<MessageId>)> {
+    pub fn take_purge(&self) -> Option<Purge> {
         self.purge_data.write().unwrap().take()
     }
+
+    /// Like `take_purge`, but only if it's still purge request `number` and
+    /// not one that's been started since
+    pub fn take_purge_if(&self, number: u32) -> Option<Purge> {
+        let mut purge = self.purge_data.write().unwrap();
+        if purge.as_ref().map_or(false, |purge| purge.number == number) {
+            purge.take()
+        } else {
+            None
+        }
+    }
+
+    /// Changes the purge in place, returning whatever `f` does, or `None`
+    /// if there isn't a purge anymore. Nothing else can touch the purge
+    /// while `f` runs, so it can check what stage it's at and move it on
+    /// without racing anyone.
+    pub fn update_purge<F, T>(&self, f: F) -> Option<T>
+    where
+        F: FnOnce(&mut Purge) -> T,
+    {
+        self.purge_data.write().unwrap().as_mut().map(f)
+    }
+}
+
+impl Purge {
+    pub fn remaining(&self) -> &[MessageId] {
+        &self.messages[self.deleted..]
+    }
+}
+
+impl Default for Ping {
+    fn default() -> Ping {
+        Ping::Everyone
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Action::Purge => "purge",
+        })
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Event::Issued => "issued",
+            Event::Used => "used",
+            Event::Expired => "expired",
+            Event::Cancelled => "cancelled",
+            Event::TwoPersonRuleOn => "two person rule turned on",
+            Event::TwoPersonRuleOff => "two person rule turned off",
+        })
+    }
 }
@@ -0,0 +1,100 @@
+use serenity::model::id::{ChannelId, MessageId};
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, RwLock};
+
+/// Channels where messages get deleted after a while, and the messages
+/// waiting to be. Pending deletions are saved so that a restart doesn't
+/// leave anything behind, a batch at a time.
+#[derive(Serialize, Deserialize, Default)]
+pub struct Channels {
+    /// How long messages last in each channel, in seconds
+    #[serde(default)]
+    channels: RwLock<HashMap<ChannelId, u64>>,
+    #[serde(default)]
+    pending: Mutex<HashMap<MessageId, Pending>>,
+    /// Messages added or removed since they were last saved
+    #[serde(skip)]
+    dirty: Mutex<HashSet<MessageId>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct Pending {
+    pub channel: ChannelId,
+    /// When it was sent and when it should go, in seconds
+    #[serde(default)]
+    pub sent: i64,
+    pub due: i64,
+}
+
+impl Channels {
+    pub fn ttl(&self, channel: ChannelId) -> Option<u64> {
+        self.channels.read().unwrap().get(&channel).cloned()
+    }
+
+    /// Makes messages in `channel` last `ttl` seconds, or stops deleting
+    /// them if that's `None`
+    pub fn set_ttl(&self, channel: ChannelId, ttl: Option<u64>) {
+        let mut channels = self.channels.write().unwrap();
+        match ttl {
+            Some(ttl) => channels.insert(channel, ttl),
+            None => channels.remove(&channel),
+        };
+    }
+
+    /// Every channel with how long messages last there
+    pub fn all(&self) -> Vec<(ChannelId, u64)> {
+        let mut all = self
+            .channels
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(&channel, &ttl)| (channel, ttl))
+            .collect::<Vec<_>>();
+        all.sort();
+        all
+    }
+
+    pub fn schedule(&self, message: MessageId, channel: ChannelId, sent: i64, due: i64) {
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(message, Pending { channel, sent, due });
+        self.dirty.lock().unwrap().insert(message);
+    }
+
+    /// Everything that's due by `now`, oldest first. They stay pending until
+    /// they're `done`.
+    pub fn due(&self, now: i64) -> Vec<(MessageId, Pending)> {
+        let mut due = self
+            .pending
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|&(_, pending)| pending.due <= now)
+            .map(|(&message, &pending)| (message, pending))
+            .collect::<Vec<_>>();
+        due.sort_by_key(|&(message, pending)| (pending.due, message));
+        due
+    }
+
+    pub fn done(&self, messages: &[MessageId]) {
+        let mut pending = self.pending.lock().unwrap();
+        let mut dirty = self.dirty.lock().unwrap();
+        for message in messages {
+            pending.remove(message);
+            dirty.insert(*message);
+        }
+    }
+
+    /// The messages that changed since the last call, with `None` for the
+    /// ones that aren't pending any more
+    pub fn take_dirty(&self) -> Vec<(MessageId, Option<Pending>)> {
+        let dirty = ::std::mem::replace(&mut *self.dirty.lock().unwrap(), HashSet::new());
+        let pending = self.pending.lock().unwrap();
+        dirty
+            .into_iter()
+            .map(|message| (message, pending.get(&message).cloned()))
+            .collect()
+    }
+}
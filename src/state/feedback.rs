@@ -0,0 +1,242 @@
+use serenity::model::id::UserId;
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::{Mutex, RwLock};
+
+use rng;
+
+/// How long a salt lasts before anonymous authors get new pseudonyms
+const SALT_LIFETIME: i64 = 7 * 24 * 60 * 60;
+/// How much feedback one person can send in an hour
+pub const MAX_PER_HOUR: usize = 5;
+/// How often staff get a digest of the feedback
+pub const DIGEST_INTERVAL: i64 = 7 * 24 * 60 * 60;
+
+/// Anonymous feedback, numbered so that staff can answer it
+#[derive(Serialize, Deserialize, Default)]
+pub struct Tickets {
+    tickets: Mutex<BTreeMap<u32, Ticket>>,
+
+    /// Whether staff only get to see a pseudonym instead of who it was
+    #[serde(default)]
+    anonymous: RwLock<bool>,
+    #[serde(default)]
+    salt: Mutex<Salt>,
+    /// Every time someone was unmasked, and by whom
+    #[serde(default)]
+    unmasked: Mutex<Vec<Unmasking>>,
+    /// When the last digest went out, in seconds
+    #[serde(default)]
+    last_digest: Mutex<i64>,
+
+    #[serde(skip)]
+    recent: Mutex<HashMap<UserId, VecDeque<i64>>>,
+    /// Tickets that one admin has asked to unmask, waiting on a second
+    #[serde(skip)]
+    unmask_requests: Mutex<HashMap<u32, UserId>>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Ticket {
+    pub author: UserId,
+    #[serde(default)]
+    pub content: String,
+    #[serde(default)]
+    pub category: Option<String>,
+    #[serde(default)]
+    pub status: Status,
+    /// When it was sent, in seconds
+    #[serde(default)]
+    pub opened: i64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+    Open,
+    Acknowledged,
+    Resolved,
+}
+
+impl Default for Status {
+    fn default() -> Status {
+        Status::Open
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Status::Open => "open",
+            Status::Acknowledged => "acknowledged",
+            Status::Resolved => "resolved",
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Salt {
+    value: u64,
+    since: i64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Unmasking {
+    pub ticket: u32,
+    pub requested_by: UserId,
+    pub confirmed_by: UserId,
+}
+
+/// How an unmask request went
+#[derive(Debug, PartialEq)]
+pub enum Unmask {
+    /// Waiting for a second admin
+    Requested,
+    /// The same admin asked twice
+    AlreadyRequested,
+    Unmasked {
+        author: UserId,
+        requested_by: UserId,
+    },
+    NoSuchTicket,
+}
+
+/// Whether it's time for a digest
+#[derive(Debug, PartialEq)]
+pub enum Digest {
+    /// There wasn't a clock yet, so this check started it
+    Started,
+    NotDue,
+    Due,
+}
+
+impl Tickets {
+    /// Opens a ticket and returns its number, counting up from 1
+    pub fn open(&self, ticket: Ticket) -> u32 {
+        let mut tickets = self.tickets.lock().unwrap();
+        let number = tickets.keys().next_back().map_or(1, |last| last + 1);
+        tickets.insert(number, ticket);
+        number
+    }
+
+    pub fn get(&self, number: u32) -> Option<Ticket> {
+        self.tickets.lock().unwrap().get(&number).cloned()
+    }
+
+    /// Changes a ticket, returning `false` if there's no such ticket
+    pub fn update<F>(&self, number: u32, f: F) -> bool
+    where
+        F: FnOnce(&mut Ticket),
+    {
+        match self.tickets.lock().unwrap().get_mut(&number) {
+            Some(ticket) => {
+                f(ticket);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Every ticket with the given status and category, oldest first.
+    /// `None` matches anything.
+    pub fn list(&self, status: Option<Status>, category: Option<&str>) -> Vec<(u32, Ticket)> {
+        self.tickets
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|&(_, ticket)| status.map_or(true, |status| ticket.status == status))
+            .filter(|&(_, ticket)| {
+                category.map_or(true, |category| {
+                    ticket.category.as_ref().map(|c| &**c) == Some(category)
+                })
+            })
+            .map(|(&number, ticket)| (number, ticket.clone()))
+            .collect()
+    }
+
+    /// Whether it's time for another digest at `now`, and marks it as sent
+    /// if so. The first check only starts the clock, so a fresh start doesn't
+    /// post an empty digest straight away.
+    pub fn digest_due(&self, now: i64) -> Digest {
+        let mut last = self.last_digest.lock().unwrap();
+        if *last == 0 {
+            *last = now;
+            return Digest::Started;
+        }
+        if now - *last < DIGEST_INTERVAL {
+            return Digest::NotDue;
+        }
+        *last = now;
+        Digest::Due
+    }
+
+    pub fn is_anonymous(&self) -> bool {
+        *self.anonymous.read().unwrap()
+    }
+
+    pub fn set_anonymous(&self, anonymous: bool) {
+        *self.anonymous.write().unwrap() = anonymous;
+    }
+
+    /// Whether `user` can send more feedback at `now` (in seconds), and
+    /// counts it if so
+    pub fn allow(&self, user: UserId, now: i64) -> bool {
+        let mut recent = self.recent.lock().unwrap();
+        let times = recent.entry(user).or_insert_with(VecDeque::new);
+        while times.front().map_or(false, |&time| now - time >= 60 * 60) {
+            times.pop_front();
+        }
+        if times.len() >= MAX_PER_HOUR {
+            return false;
+        }
+        times.push_back(now);
+        true
+    }
+
+    /// A short code that stays the same for the same person until the salt
+    /// changes, which it does every week
+    pub fn pseudonym(&self, user: UserId, now: i64) -> String {
+        let mut salt = self.salt.lock().unwrap();
+        if salt.value == 0 || now - salt.since >= SALT_LIFETIME {
+            *salt = Salt {
+                value: rng::secret(),
+                since: now,
+            };
+        }
+
+        let mut hasher = DefaultHasher::new();
+        (salt.value, user.0).hash(&mut hasher);
+        format!("{:08x}", hasher.finish() as u32)
+    }
+
+    /// Unmasking takes two different admins asking for the same ticket
+    pub fn request_unmask(&self, number: u32, admin: UserId) -> Unmask {
+        let ticket = match self.get(number) {
+            Some(ticket) => ticket,
+            None => return Unmask::NoSuchTicket,
+        };
+
+        let mut requests = self.unmask_requests.lock().unwrap();
+        match requests.get(&number).cloned() {
+            Some(first) if first == admin => Unmask::AlreadyRequested,
+            Some(first) => {
+                requests.remove(&number);
+                self.unmasked.lock().unwrap().push(Unmasking {
+                    ticket: number,
+                    requested_by: first,
+                    confirmed_by: admin,
+                });
+                Unmask::Unmasked {
+                    author: ticket.author,
+                    requested_by: first,
+                }
+            }
+            None => {
+                requests.insert(number, admin);
+                Unmask::Requested
+            }
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use std::sync::{Arc, RwLock};
+
+use super::{challenge, ephemeral, feedback, leaves, posts, pronouns, roles};
+use StaffAlertData;
+
+/// Everything that belongs to one guild
+#[derive(Default, Serialize, Deserialize)]
+pub struct GuildState {
+    #[serde(default)]
+    pub pronouns: pronouns::PronounRoles,
+    #[serde(default)]
+    pub roles: roles::Roles,
+
+    #[serde(default)]
+    pub challenge_code: challenge::Code,
+
+    #[serde(default)]
+    pub leave_counts: leaves::Counts,
+
+    #[serde(default)]
+    pub post_counts: posts::Counts,
+
+    #[serde(default)]
+    pub feedback: feedback::Tickets,
+
+    #[serde(default)]
+    pub ephemeral: ephemeral::Channels,
+
+    #[serde(default)]
+    staff_alert: RwLock<Option<Arc<StaffAlertData>>>,
+}
+
+impl GuildState {
+    /// The staff channels and roles, unless they haven't been set up yet
+    pub fn staff_alert(&self) -> Option<Arc<StaffAlertData>> {
+        self.staff_alert.read().unwrap().clone()
+    }
+
+    pub fn set_staff_alert(&self, staff_alert: StaffAlertData) {
+        *self.staff_alert.write().unwrap() = Some(Arc::new(staff_alert));
+    }
+}
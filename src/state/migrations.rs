@@ -0,0 +1,59 @@
+use json::{Map, Value};
+
+use std::env;
+
+/// The version written by `State::save`. Bump this and add a migration to
+/// the end of `MIGRATIONS` whenever the format changes in a way that
+/// `#[serde(default)]` can't cover.
+pub const CURRENT_VERSION: u32 = 2;
+
+type Migration = fn(&mut Value) -> Result<(), String>;
+
+/// `MIGRATIONS[n]` upgrades a version `n` state to version `n + 1`
+static MIGRATIONS: &[Migration] = &[unversioned, per_guild];
+
+pub fn migrate(value: &mut Value, from: u32) -> Result<(), String> {
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(from as usize) {
+        migration(value).map_err(|e| format!("Upgrading from version {}: {}", version, e))?;
+    }
+    Ok(())
+}
+
+/// The original state files had no version number, but are otherwise the
+/// same as version 1
+fn unversioned(value: &mut Value) -> Result<(), String> {
+    if !value.is_object() {
+        return Err("the state isn't a JSON object".into());
+    }
+    Ok(())
+}
+
+/// Version 2 moved everything except the dice rolls under the guild it
+/// belongs to. Before that there was only ever the one in `BOT_GUILD_ID`.
+fn per_guild(value: &mut Value) -> Result<(), String> {
+    let guild = env::var("BOT_GUILD_ID")
+        .ok()
+        .and_then(|ids| ids.split(',').next().map(|id| id.trim().to_string()))
+        .ok_or("BOT_GUILD_ID has to be set to know which guild the state belongs to")?;
+
+    let state = value
+        .as_object_mut()
+        .ok_or("the state isn't a JSON object")?;
+    let mut moved = Map::new();
+    for &key in &[
+        "pronouns",
+        "roles",
+        "challenge_code",
+        "leave_counts",
+        "post_counts",
+    ] {
+        if let Some(value) = state.remove(key) {
+            moved.insert(key.to_string(), value);
+        }
+    }
+
+    let mut guilds = Map::new();
+    guilds.insert(guild, Value::Object(moved));
+    state.insert("guilds".to_string(), Value::Object(guilds));
+    Ok(())
+}
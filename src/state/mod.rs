@@ -1,54 +1,308 @@
-use json;
+use json::{self, Value};
+use serenity::model::id::{ChannelId, GuildId, UserId};
 
-use std::{fs, io};
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, RwLock};
+
+use self::storage::invalid_data;
 
 pub mod challenge;
+pub mod ephemeral;
+pub mod feedback;
+pub mod guild;
 pub mod leaves;
+pub mod migrations;
+pub mod posts;
 pub mod pronouns;
 pub mod roles;
+pub mod rolls;
+pub mod storage;
 
 #[derive(Default, Serialize, Deserialize)]
 pub struct State {
     #[serde(default)]
-    pub pronouns: pronouns::PronounRoles,
-    #[serde(default)]
-    pub roles: roles::Roles,
+    guilds: RwLock<HashMap<GuildId, Arc<guild::GuildState>>>,
 
     #[serde(default)]
-    pub challenge_code: challenge::Code,
+    pub dice_rolls: rolls::Log,
 
-    #[serde(default)]
-    pub leave_counts: leaves::Counts,
+    #[serde(skip)]
+    storage: storage::Handle,
 }
 
-const STATE_FILE: &str = "state.json";
-const BACKUP_STATE: &str = "old_state.json";
-
 impl State {
-    pub fn load() -> State {
-        State::try_load().unwrap_or_else(|e| {
-            println!("Couldn't load state: {:?}", e);
+    /// Loads the state, upgrading it from an older version if needed. Having
+    /// nothing saved means a fresh start, but a broken save is an error so
+    /// that it doesn't get overwritten.
+    pub fn load(storage: Box<storage::Storage>) -> io::Result<State> {
+        let mut value = match storage.load()? {
+            Some(value) => value,
+            None => {
+                println!("No saved state found, starting with a fresh state");
+                let state = State {
+                    storage: storage::Handle(storage),
+                    ..State::default()
+                };
+                state.save()?;
+                return Ok(state);
+            }
+        };
+
+        let version = match value.get("version") {
+            None => 0,
+            Some(version) => version
+                .as_u64()
+                .ok_or_else(|| invalid_data("version isn't a number"))?
+                as u32,
+        };
 
-            // Make a backup of the state
-            let _ = fs::copy(STATE_FILE, BACKUP_STATE);
+        if version > migrations::CURRENT_VERSION {
+            return Err(invalid_data(format!(
+                "The saved state is version {}, but I only know up to version {}",
+                version,
+                migrations::CURRENT_VERSION
+            )));
+        }
+
+        let migrated = version < migrations::CURRENT_VERSION;
+        if migrated {
+            match storage.backup(&value, version)? {
+                Some(backup) => println!(
+                    "Upgrading the state from version {}, backed up to {}",
+                    version, backup
+                ),
+                None => println!("Upgrading the state from version {}", version),
+            }
+            migrations::migrate(&mut value, version).map_err(invalid_data)?;
+        }
+
+        let mut state: State = json::from_value(value).map_err(invalid_data)?;
+        state.storage = storage::Handle(storage);
+        if migrated {
+            state.save()?;
+        }
+        Ok(state)
+    }
+
+    /// A fresh state that's never written anywhere
+    #[cfg(test)]
+    pub fn in_memory() -> State {
+        State::load(Box::new(storage::memory::Memory::default())).unwrap()
+    }
 
-            // Create and save a new state
-            let state = State::default();
-            let _ = state.save();
-            state
-        })
+    /// What a restart would load from whatever this state has saved
+    #[cfg(test)]
+    pub fn reload(&self) -> State {
+        let memory = storage::memory::Memory::default();
+        if let Some(saved) = self.storage.0.load().unwrap() {
+            storage::Storage::save(&memory, &saved).unwrap();
+        }
+        State::load(Box::new(memory)).unwrap()
     }
 
-    fn try_load() -> io::Result<State> {
-        let data = fs::read(STATE_FILE)?;
-        Ok(json::from_slice(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?)
+    /// The state for one guild, which starts out empty
+    pub fn guild(&self, id: GuildId) -> Arc<guild::GuildState> {
+        if let Some(guild) = self.guilds.read().unwrap().get(&id) {
+            return guild.clone();
+        }
+        self.guilds
+            .write()
+            .unwrap()
+            .entry(id)
+            .or_insert_with(Default::default)
+            .clone()
     }
 
     pub fn save(&self) -> io::Result<()> {
-        let data = json::to_vec(self).unwrap();
-        fs::write(STATE_FILE, data)?;
-        Ok(())
+        let mut value = json::to_value(self).map_err(invalid_data)?;
+        value["version"] = Value::from(migrations::CURRENT_VERSION);
+        self.storage.0.save(&value)
+    }
+
+    /// Saves just the given entries if the backend can, or everything if not
+    pub fn save_entries(&self, entries: &[storage::Entry]) -> io::Result<()> {
+        if entries.is_empty() || self.storage.0.save_entries(entries)? {
+            return Ok(());
+        }
+        self.save()
+    }
+
+    /// Saves everything for one guild
+    pub fn save_guild(&self, id: GuildId) -> io::Result<()> {
+        let guild = json::to_value(&*self.guild(id)).map_err(invalid_data)?;
+        self.save_entries(&[(path(&["guilds", &id.0.to_string()]), Some(guild))])
+    }
+
+    /// Saves the log of dice rolls, so that they can be replayed later
+    pub fn save_dice_rolls(&self) -> io::Result<()> {
+        let rolls = json::to_value(&self.dice_rolls).map_err(invalid_data)?;
+        self.save_entries(&[(path(&["dice_rolls"]), Some(rolls))])
+    }
+
+    pub fn save_leave_count(&self, guild: GuildId, user: UserId) -> io::Result<()> {
+        let count = self.guild(guild).leave_counts.get(user);
+        self.save_entries(&[(
+            path(&[
+                "guilds",
+                &guild.0.to_string(),
+                "leave_counts",
+                "counts",
+                &user.0.to_string(),
+            ]),
+            Some(Value::from(count)),
+        )])
     }
-}
 
+    /// Saves the post counts that have changed since the last time
+    pub fn save_post_counts(&self) -> io::Result<()> {
+        let guilds = self.guilds.read().unwrap().clone();
+        let mut entries = Vec::new();
+        for (id, guild) in guilds {
+            entries.extend(guild.post_counts.take_dirty().into_iter().map(
+                |(user, channel, count)| {
+                    (
+                        path(&[
+                            "guilds",
+                            &id.0.to_string(),
+                            "post_counts",
+                            "counts",
+                            &user.0.to_string(),
+                            &channel.0.to_string(),
+                        ]),
+                        Some(Value::from(count)),
+                    )
+                },
+            ));
+        }
+        self.save_entries(&entries)
+    }
+
+    pub fn save_staff_alert(&self, guild: GuildId) -> io::Result<()> {
+        let staff_alert = self.guild(guild).staff_alert();
+        let value = json::to_value(&staff_alert).map_err(invalid_data)?;
+        self.save_entries(&[(
+            path(&["guilds", &guild.0.to_string(), "staff_alert"]),
+            Some(value),
+        )])
+    }
+
+    pub fn save_ticket(&self, guild: GuildId, number: u32) -> io::Result<()> {
+        let ticket = self.guild(guild).feedback.get(number);
+        let value = json::to_value(&ticket).map_err(invalid_data)?;
+        self.save_entries(&[(
+            path(&[
+                "guilds",
+                &guild.0.to_string(),
+                "feedback",
+                "tickets",
+                &number.to_string(),
+            ]),
+            Some(value),
+        )])
+    }
 
+    /// Saves whether feedback is anonymous, the salt, the unmask log and when
+    /// the last digest went out
+    pub fn save_feedback_settings(&self, guild: GuildId) -> io::Result<()> {
+        let feedback = json::to_value(&self.guild(guild).feedback).map_err(invalid_data)?;
+        let entries = ["anonymous", "salt", "unmasked", "last_digest"]
+            .iter()
+            .map(|&key| {
+                (
+                    path(&["guilds", &guild.0.to_string(), "feedback", key]),
+                    feedback.get(key).cloned(),
+                )
+            })
+            .collect::<Vec<_>>();
+        self.save_entries(&entries)
+    }
+
+    pub fn save_ephemeral_channel(&self, guild: GuildId, channel: ChannelId) -> io::Result<()> {
+        let ttl = self.guild(guild).ephemeral.ttl(channel);
+        self.save_entries(&[(
+            path(&[
+                "guilds",
+                &guild.0.to_string(),
+                "ephemeral",
+                "channels",
+                &channel.0.to_string(),
+            ]),
+            ttl.map(Value::from),
+        )])
+    }
+
+    /// Saves whether each of `messages` is still waiting to be deleted
+    pub fn save_pending_deletes(&self, guild: GuildId) -> io::Result<()> {
+        let dirty = self.guild(guild).ephemeral.take_dirty();
+        let mut entries = Vec::with_capacity(dirty.len());
+        for (message, pending) in dirty {
+            let pending = match pending {
+                Some(pending) => Some(json::to_value(&pending).map_err(invalid_data)?),
+                None => None,
+            };
+            entries.push((
+                path(&[
+                    "guilds",
+                    &guild.0.to_string(),
+                    "ephemeral",
+                    "pending",
+                    &message.0.to_string(),
+                ]),
+                pending,
+            ));
+        }
+        self.save_entries(&entries)
+    }
+
+    /// Saves how long challenge codes last, who they ping, the log of them,
+    /// the two person rule and the purge request count
+    pub fn save_challenge_settings(&self, guild: GuildId) -> io::Result<()> {
+        let code = json::to_value(&self.guild(guild).challenge_code).map_err(invalid_data)?;
+        let entries = ["expiry", "ping", "log", "two_person_rule", "requests"]
+            .iter()
+            .map(|&key| {
+                (
+                    path(&["guilds", &guild.0.to_string(), "challenge_code", key]),
+                    code.get(key).cloned(),
+                )
+            })
+            .collect::<Vec<_>>();
+        self.save_entries(&entries)
+    }
+
+    pub fn save_purge(&self, guild: GuildId) -> io::Result<()> {
+        let purge = match self.guild(guild).challenge_code.purge() {
+            Some(purge) => Some(json::to_value(&purge).map_err(invalid_data)?),
+            None => None,
+        };
+        self.save_entries(&[(
+            path(&[
+                "guilds",
+                &guild.0.to_string(),
+                "challenge_code",
+                "purge_data",
+            ]),
+            purge,
+        )])
+    }
+
+    pub fn save_alias(&self, guild: GuildId, alias: &str) -> io::Result<()> {
+        let target = self
+            .guild(guild)
+            .roles
+            .aliases
+            .read()
+            .unwrap()
+            .get(alias)
+            .cloned();
+        self.save_entries(&[(
+            path(&["guilds", &guild.0.to_string(), "roles", "aliases", alias]),
+            target.map(Value::from),
+        )])
+    }
+}
+
+fn path(keys: &[&str]) -> Vec<String> {
+    keys.iter().map(|k| k.to_string()).collect()
+}
@@ -0,0 +1,52 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use serenity::model::id::{ChannelId, UserId};
+
+/// Saving the whole state for every single message would be a bit much, so
+/// the counts are only flushed this often, in seconds
+pub const FLUSH_INTERVAL: u64 = 60;
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct Counts {
+    counts: Mutex<HashMap<UserId, HashMap<ChannelId, u32>>>,
+    /// Counts that changed since they were last saved
+    #[serde(skip)]
+    dirty: Mutex<HashSet<(UserId, ChannelId)>>,
+}
+
+impl Counts {
+    pub fn increment(&self, user: UserId, channel: ChannelId) -> u32 {
+        let mut map = self.counts.lock().unwrap();
+        let count = map
+            .entry(user)
+            .or_insert_with(HashMap::new)
+            .entry(channel)
+            .or_insert(0);
+        *count += 1;
+        self.dirty.lock().unwrap().insert((user, channel));
+        *count
+    }
+
+    pub fn get(&self, user: UserId) -> HashMap<ChannelId, u32> {
+        self.counts
+            .lock()
+            .unwrap()
+            .get(&user)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// The counts that changed since the last call
+    pub fn take_dirty(&self) -> Vec<(UserId, ChannelId, u32)> {
+        let dirty = ::std::mem::replace(&mut *self.dirty.lock().unwrap(), HashSet::new());
+        let map = self.counts.lock().unwrap();
+        dirty
+            .into_iter()
+            .filter_map(|(user, channel)| {
+                let count = *map.get(&user)?.get(&channel)?;
+                Some((user, channel, count))
+            })
+            .collect()
+    }
+}
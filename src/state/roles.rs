@@ -5,7 +5,9 @@ use std::collections::HashMap;
 
 #[derive(Default, Serialize, Deserialize)]
 pub struct Roles {
+    #[serde(default)]
     pub roles: RwLock<HashMap<String, RoleId>>,
+    #[serde(default)]
     pub aliases: RwLock<HashMap<String, String>>,
 }
 
@@ -0,0 +1,68 @@
+use serenity::model::id::UserId;
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+const MAX_ENTRIES: usize = 1000;
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct Log {
+    rolls: Mutex<VecDeque<Entry>>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Entry {
+    pub seed: u32,
+    pub user: UserId,
+    pub expression: String,
+}
+
+impl Log {
+    pub fn record(&self, entry: Entry) {
+        let mut rolls = self.rolls.lock().unwrap();
+        rolls.push_back(entry);
+        while rolls.len() > MAX_ENTRIES {
+            rolls.pop_front();
+        }
+    }
+
+    pub fn find(&self, seed: u32) -> Option<Entry> {
+        let rolls = self.rolls.lock().unwrap();
+        rolls.iter().rev().find(|e| e.seed == seed).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(seed: u32, expression: &str) -> Entry {
+        Entry {
+            seed,
+            user: UserId(1),
+            expression: expression.into(),
+        }
+    }
+
+    #[test]
+    fn finds_the_latest_roll_with_a_seed() {
+        let log = Log::default();
+        log.record(entry(1, "1d6"));
+        log.record(entry(2, "2d6"));
+        log.record(entry(1, "3d6"));
+
+        assert_eq!(log.find(1).unwrap().expression, "3d6");
+        assert_eq!(log.find(2).unwrap().expression, "2d6");
+        assert!(log.find(3).is_none());
+    }
+
+    #[test]
+    fn forgets_the_oldest_rolls() {
+        let log = Log::default();
+        for seed in 0..MAX_ENTRIES as u32 + 1 {
+            log.record(entry(seed, "1d6"));
+        }
+        assert!(log.find(0).is_none());
+        assert!(log.find(1).is_some());
+    }
+}
@@ -0,0 +1,77 @@
+use json::{self, Value};
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use super::{invalid_data, Storage};
+
+pub const STATE_FILE: &str = "state.json";
+
+/// Keeps the whole state in one JSON file
+pub struct JsonFile {
+    path: String,
+    save_lock: Mutex<()>,
+}
+
+impl JsonFile {
+    pub fn new(path: &str) -> JsonFile {
+        JsonFile {
+            path: path.to_string(),
+            save_lock: Mutex::new(()),
+        }
+    }
+}
+
+impl Storage for JsonFile {
+    fn load(&self) -> io::Result<Option<Value>> {
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        Ok(Some(json::from_slice(&data).map_err(invalid_data)?))
+    }
+
+    fn save(&self, state: &Value) -> io::Result<()> {
+        let _lock = self.save_lock.lock().unwrap();
+        write_atomically(Path::new(&self.path), state)
+    }
+
+    /// Keeps the old version next to the state file, e.g. `state.v2.json`
+    fn backup(&self, state: &Value, version: u32) -> io::Result<Option<String>> {
+        let path = Path::new(&self.path);
+        let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("state");
+        let backup = path.with_file_name(format!("{}.v{}.json", stem, version));
+        write_atomically(&backup, state)?;
+        Ok(Some(backup.display().to_string()))
+    }
+}
+
+/// Writes the JSON to a temporary file and then renames it over the old one,
+/// so a crash part way through never leaves a half-written file.
+fn write_atomically(path: &Path, value: &Value) -> io::Result<()> {
+    let data = json::to_vec(value).map_err(invalid_data)?;
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+
+    let mut file = File::create(&temp)?;
+    file.write_all(&data)?;
+    file.sync_all()?;
+    drop(file);
+
+    fs::rename(&temp, path)?;
+
+    #[cfg(unix)]
+    {
+        // Make sure the rename itself makes it to disk
+        let dir = match path.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+        let _ = File::open(dir).and_then(|dir| dir.sync_all());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,23 @@
+use json::Value;
+
+use std::io;
+use std::sync::Mutex;
+
+use super::Storage;
+
+/// Keeps the state in memory, so that tests don't touch the disk
+#[derive(Default)]
+pub struct Memory {
+    saved: Mutex<Option<Value>>,
+}
+
+impl Storage for Memory {
+    fn load(&self) -> io::Result<Option<Value>> {
+        Ok(self.saved.lock().unwrap().clone())
+    }
+
+    fn save(&self, state: &Value) -> io::Result<()> {
+        *self.saved.lock().unwrap() = Some(state.clone());
+        Ok(())
+    }
+}
@@ -0,0 +1,67 @@
+use json::Value;
+
+use std::io;
+
+pub mod json_file;
+#[cfg(test)]
+pub mod memory;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+/// Somewhere to keep the state between runs. Backends deal in the state's
+/// JSON form, so versioning and migrations work the same for all of them.
+pub trait Storage: Send + Sync {
+    /// Reads back everything that was saved, or `None` if nothing has been
+    fn load(&self) -> io::Result<Option<Value>>;
+
+    /// Replaces everything that's saved
+    fn save(&self, state: &Value) -> io::Result<()>;
+
+    /// Saves a handful of entries without touching the rest. Each entry is
+    /// the path to it in the JSON and its new value, or `None` to remove it.
+    /// Backends that can only write everything at once return `Ok(false)`,
+    /// and get a full `save` instead.
+    fn save_entries(&self, entries: &[Entry]) -> io::Result<bool> {
+        let _ = entries;
+        Ok(false)
+    }
+
+    /// Keeps a copy of the state from before a migration, returning where it
+    /// went. Backends without anywhere sensible to put one return `Ok(None)`.
+    fn backup(&self, state: &Value, version: u32) -> io::Result<Option<String>> {
+        let _ = (state, version);
+        Ok(None)
+    }
+}
+
+pub type Entry = (Vec<String>, Option<Value>);
+
+pub struct Handle(pub Box<Storage>);
+
+impl Default for Handle {
+    fn default() -> Handle {
+        Handle(Box::new(json_file::JsonFile::new(json_file::STATE_FILE)))
+    }
+}
+
+/// Picks the backend from `STATE_BACKEND`, which is either `json` (the
+/// default) or `sqlite`
+pub fn from_env() -> io::Result<Box<Storage>> {
+    let backend = ::std::env::var("STATE_BACKEND").unwrap_or_else(|_| "json".into());
+    match &backend[..] {
+        "json" => Ok(Box::new(json_file::JsonFile::new(json_file::STATE_FILE))),
+        #[cfg(feature = "sqlite")]
+        "sqlite" => Ok(Box::new(sqlite::Sqlite::open(&sqlite::db_path())?)),
+        other => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Unknown or disabled STATE_BACKEND `{}`", other),
+        )),
+    }
+}
+
+pub fn invalid_data<E>(e: E) -> io::Error
+where
+    E: Into<Box<::std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
@@ -0,0 +1,258 @@
+use json::{self, Map, Value};
+use rusqlite::types::ToSql;
+use rusqlite::{self, Connection};
+
+use std::env;
+use std::io;
+use std::sync::Mutex;
+
+use super::json_file::JsonFile;
+use super::{invalid_data, Entry, Storage};
+
+const DEFAULT_DB: &str = "state.db";
+
+/// Where the database lives, from `STATE_DB`
+pub fn db_path() -> String {
+    env::var("STATE_DB").unwrap_or_else(|_| DEFAULT_DB.into())
+}
+
+/// Keeps the state in SQLite, one row per entry. Nested objects are split
+/// up into rows keyed by their JSON pointer, so that e.g. one member's leave
+/// count can be updated without writing out everything else.
+pub struct Sqlite {
+    conn: Mutex<Connection>,
+}
+
+impl Sqlite {
+    pub fn open(path: &str) -> io::Result<Sqlite> {
+        let conn = Connection::open(path).map_err(sql_error)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS entries (
+                 path TEXT PRIMARY KEY NOT NULL,
+                 value TEXT NOT NULL
+             );",
+        )
+        .map_err(sql_error)?;
+        Ok(Sqlite {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    pub fn is_empty(&self) -> io::Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM entries", &[], |row| row.get(0))
+            .map_err(sql_error)?;
+        Ok(count == 0)
+    }
+}
+
+impl Storage for Sqlite {
+    fn load(&self) -> io::Result<Option<Value>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare("SELECT path, value FROM entries")
+            .map_err(sql_error)?;
+        let rows = stmt
+            .query_map(&[], |row| {
+                (row.get::<_, String>(0), row.get::<_, String>(1))
+            })
+            .map_err(sql_error)?;
+
+        let mut root = None;
+        for row in rows {
+            let (path, value) = row.map_err(sql_error)?;
+            let value = json::from_str(&value).map_err(invalid_data)?;
+            let root = root.get_or_insert_with(|| Value::Object(Map::new()));
+            insert(root, &split_pointer(&path), value);
+        }
+        Ok(root)
+    }
+
+    fn save(&self, state: &Value) -> io::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(sql_error)?;
+        tx.execute("DELETE FROM entries", &[]).map_err(sql_error)?;
+        insert_rows(&tx, "", state)?;
+        tx.commit().map_err(sql_error)
+    }
+
+    fn save_entries(&self, entries: &[Entry]) -> io::Result<bool> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(sql_error)?;
+        for &(ref path, ref value) in entries {
+            let pointer = to_pointer(path);
+
+            // Clear out the old value, anything under it, and anything above
+            // it that used to be a leaf
+            let subtree = format!("{}/", pointer);
+            tx.execute(
+                "DELETE FROM entries WHERE path = ?1 OR substr(path, 1, ?2) = ?3",
+                &[
+                    &pointer as &ToSql,
+                    &(subtree.chars().count() as i64),
+                    &subtree,
+                ],
+            )
+            .map_err(sql_error)?;
+            for i in 0..path.len() {
+                tx.execute(
+                    "DELETE FROM entries WHERE path = ?1",
+                    &[&to_pointer(&path[..i])],
+                )
+                .map_err(sql_error)?;
+            }
+
+            match *value {
+                Some(ref value) => insert_rows(&tx, &pointer, value)?,
+                // A map that just lost its last entry is still there, just
+                // empty, and needs a row of its own to say so
+                None if !path.is_empty() => {
+                    let parent = to_pointer(&path[..path.len() - 1]);
+                    let children = format!("{}/", parent);
+                    let left: i64 = tx
+                        .query_row(
+                            "SELECT COUNT(*) FROM entries WHERE substr(path, 1, ?1) = ?2",
+                            &[&(children.chars().count() as i64) as &ToSql, &children],
+                            |row| row.get(0),
+                        )
+                        .map_err(sql_error)?;
+                    if left == 0 {
+                        insert_rows(&tx, &parent, &Value::Object(Map::new()))?;
+                    }
+                }
+                None => {}
+            }
+        }
+        tx.commit().map_err(sql_error)?;
+        Ok(true)
+    }
+}
+
+/// Copies everything saved in a JSON file into an empty database, returning
+/// how many rows that made. The state is copied as it is, so any upgrades
+/// happen the next time the bot loads it.
+pub fn import(from: &JsonFile, to: &Sqlite) -> io::Result<usize> {
+    if !to.is_empty()? {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "the database already has a state in it",
+        ));
+    }
+
+    let value = from
+        .load()?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "there's no state to copy"))?;
+    to.save(&value)?;
+
+    let conn = to.conn.lock().unwrap();
+    let count: i64 = conn
+        .query_row("SELECT COUNT(*) FROM entries", &[], |row| row.get(0))
+        .map_err(sql_error)?;
+    Ok(count as usize)
+}
+
+fn insert_rows(conn: &Connection, pointer: &str, value: &Value) -> io::Result<()> {
+    match *value {
+        Value::Object(ref map) if !map.is_empty() => {
+            for (key, value) in map {
+                insert_rows(conn, &format!("{}/{}", pointer, escape(key)), value)?;
+            }
+        }
+        _ => {
+            conn.execute(
+                "INSERT INTO entries (path, value) VALUES (?1, ?2)",
+                &[&pointer as &ToSql, &value.to_string()],
+            )
+            .map_err(sql_error)?;
+        }
+    }
+    Ok(())
+}
+
+fn insert(root: &mut Value, path: &[String], value: Value) {
+    let mut node = root;
+    for key in path {
+        if !node.is_object() {
+            *node = Value::Object(Map::new());
+        }
+        node = { node }
+            .as_object_mut()
+            .unwrap()
+            .entry(key.clone())
+            .or_insert(Value::Null);
+    }
+    *node = value;
+}
+
+fn to_pointer(path: &[String]) -> String {
+    path.iter().map(|key| format!("/{}", escape(key))).collect()
+}
+
+fn split_pointer(pointer: &str) -> Vec<String> {
+    pointer
+        .split('/')
+        .skip(1)
+        .map(|key| key.replace("~1", "/").replace("~0", "~"))
+        .collect()
+}
+
+fn escape(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+fn sql_error(e: rusqlite::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serenity::model::id::{GuildId, RoleId};
+    use state::State;
+
+    fn load(db: &Sqlite) -> Value {
+        db.load().unwrap().unwrap()
+    }
+
+    #[test]
+    fn removing_the_last_entry_leaves_an_empty_map() {
+        let db = Sqlite::open(":memory:").unwrap();
+        let state = r#"{"roles": {"roles": {"cat": 5}, "aliases": {"kitty": "cat"}}}"#;
+        db.save(&json::from_str(state).unwrap()).unwrap();
+
+        let path = vec!["roles".into(), "aliases".into(), "kitty".into()];
+        db.save_entries(&[(path, None)]).unwrap();
+
+        let saved = load(&db);
+        assert_eq!(saved["roles"]["aliases"], Value::Object(Map::new()));
+        assert_eq!(saved["roles"]["roles"]["cat"], Value::from(5));
+
+        let path = vec!["roles".into(), "aliases".into(), "kitten".into()];
+        db.save_entries(&[(path, Some(Value::from("cat")))])
+            .unwrap();
+        assert_eq!(load(&db)["roles"]["aliases"]["kitten"], Value::from("cat"));
+    }
+
+    #[test]
+    fn removing_the_only_alias_still_loads() {
+        let state = State::load(Box::new(Sqlite::open(":memory:").unwrap())).unwrap();
+        let guild = GuildId(1);
+        let guild_state = state.guild(guild);
+        let roles = &guild_state.roles;
+        roles.roles.write().unwrap().insert("cat".into(), RoleId(5));
+        roles
+            .aliases
+            .write()
+            .unwrap()
+            .insert("kitty".into(), "cat".into());
+        state.save_guild(guild).unwrap();
+
+        roles.aliases.write().unwrap().remove("kitty");
+        state.save_alias(guild, "kitty").unwrap();
+
+        let reloaded = state.reload();
+        assert!(reloaded.guild(guild).roles.all_aliases().is_empty());
+        assert_eq!(reloaded.guild(guild).roles.all_roles().len(), 1);
+    }
+}